@@ -1,24 +1,82 @@
-use clap::{Args, Parser};
+use clap::{ArgAction, Args, Parser};
+use log::LevelFilter;
 use magnet_url::Magnet;
 
 use std::sync::Arc;
-use tea_torrent::Torrent;
+use std::thread;
+use tea_torrent::{Event, EventKind, Torrent};
 
 fn main() {
-    println!("tTorrent {}\n", env!("CARGO_PKG_VERSION"));
     // println!("\x1b]0;tTorrent\x07");
     let args = TTArgs::parse();
+    if !args.quiet {
+        println!("tTorrent {}\n", env!("CARGO_PKG_VERSION"));
+    }
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    //RUST_LOG can still pick targets, e.g. RUST_LOG=peer=trace
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_env("RUST_LOG")
+        .init();
 
     if let Some(link) = args.source.magnet_link {
         println!("Magnet links are not yet supported");
         println!("{:?}", Magnet::new(&link));
         return
     }
-    let torrent = Torrent::new(args.source.torrent_file.unwrap(), args.destination, None);
+    let torrent = match Torrent::new(args.source.torrent_file.unwrap(), args.destination, None) {
+        Ok(torrent) => torrent,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if !args.quiet {
+        println!("{}\n", torrent.torrent_file);
+        let events = torrent.subscribe();
+        //ends with the torrent, the sender goes with it
+        thread::spawn(move || events.iter().for_each(print_event));
+    }
 
     let t = Arc::new(torrent);
     let jh = tea_torrent::run_torrent(t);
-    let _result = jh.join();
+    if let Ok(Err(e)) = jh.join() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn print_event(event: Event) {
+    match event.kind {
+        EventKind::StateChanged { to, .. } => println!("{:?}", to),
+        EventKind::PieceFinished { piece } => println!("\x1b[92mPiece {} done\x1b[0m", piece),
+        EventKind::HashFailed { piece } => {
+            println!("\x1b[91mHash doesn't match!\x1b[0m Piece {}", piece)
+        }
+        EventKind::PeerConnected { addr, client, .. } => {
+            println!("\x1b[1mConnected\x1b[0m {} {}", addr, client)
+        }
+        EventKind::PeerDisconnected {
+            addr,
+            error: Some(e),
+            ..
+        } => println!("\x1b[91mDropped\x1b[0m {}: {}", addr, e),
+        EventKind::PeerBanned { ip } => println!("\x1b[91mBanned\x1b[0m {}", ip),
+        EventKind::TrackerReply { url, peers, .. } => println!("{} returned {} peers", url, peers),
+        EventKind::TrackerError { error, .. } => println!("\x1b[91mTracker\x1b[0m {}", error),
+        EventKind::TorrentFinished => println!("\x1b[92mDownload complete\x1b[0m"),
+        EventKind::StorageError { path, error } => {
+            println!("\x1b[91mError\x1b[0m {}: {}", path.display(), error)
+        }
+        _ => (),
+    }
 }
 
 /// CLI version of TeaTorrent. Downloads one torrent at a time.
@@ -31,6 +89,14 @@ struct TTArgs {
 
     /// Download destination
     destination: Option<String>,
+
+    /// Log more, up to -vvv for every message to and from peers
+    #[clap(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Print nothing but errors
+    #[clap(short, long)]
+    quiet: bool,
 }
 
 #[derive(Args, Debug)]
@@ -1,55 +1,122 @@
+use crate::event::{Event, EventKind, EventStream, Subscribers};
+use crate::resume::{FileState, PartialPiece, ResumeData};
+use crate::storage::{FileStorage, Storage};
 use crate::tf;
 use crate::BLOCK_SIZE;
+use crate::{Error, FileAttributes, GoalAction, InfoHash, SeedGoal, TorrentState};
 use dirs;
-use fs::OpenOptions;
+use log::{debug, error, info};
 use sha1::Digest;
 use sha1::Sha1;
-use std::fs;
-use std::io::Read;
-use std::io::Seek;
-use std::io::SeekFrom;
-use std::io::Write;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::ops::Range;
 use std::path::PathBuf;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use tf::TorrentFile;
 // use threadpool::ThreadPool;
+mod buffer;
+pub use buffer::{BufferPool, PieceBuffer};
+
+/// Memory pieces in flight may take unless told otherwise.
+const PIECE_MEMORY: usize = 64 * 1024 * 1024;
+
+/// The 20 byte id a peer sent in its handshake.
+pub type PeerId = [u8; 20];
+
+pub type ErrorCallback = Box<dyn Fn(&Error) + 'static + Send + Sync>;
 
 #[derive(Debug)]
 pub struct Content {
     pub pieces: Vec<Mutex<Piece>>,
-    files: Vec<(PathBuf, usize)>,
+    files: Vec<ContentFile>,
     pub destination_path: String,
     pub events: ContentEvents,
+    stream: EventStream,
+    //hex, for logging
+    info_hash: String,
+    priorities: RwLock<Vec<FilePriority>>,
+    storage: Box<dyn Storage>,
+    buffers: Arc<BufferPool>,
+    //created empty by the last preallocate, there is nothing in them to hash
+    fresh: Mutex<HashSet<usize>>,
+    //what wanted_pieces returns, built again after a piece or priority changes
+    wanted: Mutex<Option<Arc<Vec<u32>>>>,
+}
+
+/// How much the application wants a file. Pieces are requested in order of
+/// the highest priority file they touch, `Skip` files are not downloaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilePriority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// A file of the torrent as it is laid out on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentFile {
+    pub path: PathBuf,
+    pub length: usize,
+    pub attributes: FileAttributes,
+    /// Link target relative to the directory the link is in.
+    pub symlink: Option<PathBuf>,
+}
+
+impl ContentFile {
+    /// Pad files and symlinks take part in piece hashing as zeros,
+    /// but nothing is ever written to them.
+    pub fn has_data(&self) -> bool {
+        !self.attributes.pad && self.symlink.is_none()
+    }
 }
 
 impl Content {
-    pub fn new(tf: &TorrentFile, dir_path_string: Option<String>) -> Content {
+    /// Without a `dir_path_string` it goes to the system's download folder.
+    pub fn new(tf: &TorrentFile, dir_path_string: Option<String>) -> Result<Content, Error> {
         let dir_path_string = match dir_path_string {
             Some(path) => path,
             None => dirs::download_dir()
-                .unwrap()
-                .into_os_string()
-                .into_string()
-                .unwrap(),
+                .and_then(|dir| dir.into_os_string().into_string().ok())
+                .ok_or(Error::NoDownloadDir)?,
         };
-        let file_path = format!(
-            "{}/{}",
-            dir_path_string,
-            if tf.info.files.len() > 1 {
-                &tf.info.name
-            } else {
-                ""
-            }
-        );
+        let mut file_path = PathBuf::from(&dir_path_string);
+        if tf.info.files.len() > 1 {
+            file_path.push(&tf.info.name);
+        }
         //I think im converting tfFile to content File here
-        let files: Vec<(PathBuf, usize)> = tf
+        //paths are sanitized in tf, so every component stays inside file_path
+        let files: Vec<ContentFile> = tf
             .info
             .files
             .iter()
-            .map(|f| -> (PathBuf, usize) {
-                let path = format!("{}/{}", file_path, f.path);
-                (PathBuf::from(path), f.length)
+            .map(|f| {
+                let mut path = file_path.clone();
+                path.extend(f.components());
+                //symlink path is relative to the torrent root, the link itself is
+                //nested, so climb back up first
+                let symlink = f.symlink_path.as_ref().map(|target| {
+                    let mut link = PathBuf::new();
+                    for _ in 1..f.components().count() {
+                        link.push("..");
+                    }
+                    link.extend(target.split('/'));
+                    link
+                });
+                ContentFile {
+                    path,
+                    length: f.length,
+                    attributes: f.attributes,
+                    symlink,
+                }
             })
             .collect();
 
@@ -69,7 +136,6 @@ impl Content {
             pieces.push(Mutex::new(Piece::new(
                 piece_number,
                 tf.info.piece_length,
-                tf.info.piece_length / BLOCK_SIZE,
                 offset,
                 piece_files,
                 hash,
@@ -87,135 +153,609 @@ impl Content {
             .get_piece_hash((tf.info.piece_count - 1) as usize)
             .try_into()
             .unwrap();
-        //because it's size is unconvential, smaller blocks are needed
-        let last_piece_size = tf.info.get_last_piece_size();
-        let mut remainder = last_piece_size % BLOCK_SIZE;
-        let mut smaller_blocks = 0;
-        while remainder != 0 {
-            remainder = remainder & (remainder - 1);
-            smaller_blocks += 1;
-        }
-        let block_count_goal = last_piece_size / BLOCK_SIZE + smaller_blocks;
-
         pieces.push(Mutex::new(Piece::new(
             tf.info.piece_count - 1,
-            last_piece_size,
-            block_count_goal,
+            tf.info.get_last_piece_size(),
             offset,
             piece_files,
             hash,
         )));
 
-        Content {
+        Ok(Content {
             pieces,
+            priorities: RwLock::new(vec![FilePriority::Normal; files.len()]),
+            storage: Box::new(FileStorage::new(&files)),
+            buffers: BufferPool::new(std::cmp::max(
+                PIECE_MEMORY / tf.info.piece_length as usize,
+                4,
+            )),
             files,
+            fresh: Mutex::new(HashSet::new()),
+            wanted: Mutex::new(None),
             destination_path: dir_path_string,
             events: ContentEvents::new(),
+            stream: EventStream::new(*tf.info_hash.raw()),
+            info_hash: tf.info_hash.as_string(),
+        })
+    }
+
+    pub fn files(&self) -> &[ContentFile] {
+        &self.files
+    }
+
+    /// Events of the torrent from now on, see `Torrent::subscribe`.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.stream.subscribe()
+    }
+
+    /// Events go to the session's subscribers too.
+    pub(crate) fn set_session_events(&mut self, session: Arc<Subscribers>) {
+        self.stream.set_session(session);
+    }
+
+    pub(crate) fn emit(&self, kind: EventKind) {
+        self.stream.emit(kind);
+    }
+
+    /// Replaces the default `FileStorage`, has to happen before the torrent runs.
+    pub fn set_storage(&mut self, storage: Box<dyn Storage>) {
+        self.storage = storage;
+    }
+
+    pub fn storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
+    }
+
+    /// Makes every piece written so far durable.
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.storage.flush()
+    }
+
+    /// How many pieces may be downloaded at the same time, each one holds
+    /// a buffer of the piece length until it is written.
+    pub fn set_piece_buffer_limit(&self, limit: usize) {
+        self.buffers.set_limit(limit)
+    }
+
+    /// Marks the blocks of a piece nobody was asked for yet as requested
+    /// from `peer` and returns them. `None` if there are none, or too many
+    /// pieces are in flight to start a new one.
+    pub fn request_piece(&self, piece: usize, peer: &PeerId) -> Option<Vec<(u32, u32)>> {
+        self.pieces[piece]
+            .lock()
+            .unwrap()
+            .request(peer, &self.buffers)
+    }
+
+    /// Gives the blocks requested from `peer` back, for requests that
+    /// couldn't be sent.
+    pub fn cancel_piece(&self, piece: usize, peer: &PeerId) {
+        self.pieces[piece].lock().unwrap().cancel(peer);
+    }
+
+    /// Gives back everything requested from a peer that is gone.
+    pub fn cancel_peer(&self, peer: &PeerId) {
+        for piece in &self.pieces {
+            piece.lock().unwrap().cancel(peer);
         }
     }
 
-    pub fn preallocate(&self) {
-        println!("Preallocating files");
+    /// Addresses found to have sent corrupt data for a piece, each one only
+    /// returned once. Known as soon as a piece fails that came from one
+    /// address alone, otherwise once it passes and the blocks can be compared.
+    pub fn take_corrupt_peers(&self, piece: usize) -> Vec<IpAddr> {
+        std::mem::take(&mut self.pieces[piece].lock().unwrap().culprits)
+    }
+
+    pub fn block_states(&self, piece: usize) -> Vec<BlockState> {
+        self.pieces[piece].lock().unwrap().block_states.clone()
+    }
+
+    /// Flushes and closes every open file.
+    pub fn close(&self) -> std::io::Result<()> {
+        self.storage.close()
+    }
+
+    /// Removes every file of the torrent from storage.
+    pub fn delete_files(&self) -> std::io::Result<()> {
+        (0..self.files.len()).try_for_each(|file| self.storage.delete(file))
+    }
+
+    /// How many files may be open at once, 64 by default.
+    pub fn set_open_file_limit(&self, limit: usize) {
+        self.storage.set_open_file_limit(limit)
+    }
+
+    pub fn file_priorities(&self) -> Vec<FilePriority> {
+        self.priorities.read().unwrap().clone()
+    }
+
+    /// Can be called while the torrent runs. A file that stops being skipped
+    /// is allocated and the pieces it shares with other files are checked
+    /// again, their part of it was never written. If the file can't be
+    /// allocated that goes to the `error` event.
+    /// False if there is no such file.
+    pub fn set_file_priority(&self, file: usize, priority: FilePriority) -> bool {
+        let old = match self.priorities.write().unwrap().get_mut(file) {
+            Some(current) => std::mem::replace(current, priority),
+            None => return false,
+        };
+        self.forget_wanted();
+        if old != FilePriority::Skip || priority == FilePriority::Skip {
+            return true;
+        }
 
-        for file in &self.files {
-            if let Some(dir_path) = file.0.as_path().parent() {
-                fs::create_dir_all(dir_path).unwrap();
+        if let Err(e) = self.preallocate_file(file) {
+            self.report_error(e);
+            return true;
+        }
+        for piece in &self.pieces {
+            let mut piece = piece.lock().unwrap();
+            if piece.files.contains(&file) && piece.status == PieceStatus::Available {
+                let mut buf = vec![];
+                read_piece(self.storage(), &self.files, &piece, &mut buf);
+                piece.check_hash(&buf);
             }
-            //preallocate file
-            let f = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(file.0.as_path())
-                .unwrap();
-            f.set_len(file.1 as u64).unwrap();
         }
-        println!("Preallocation complete");
+        //the ones that failed are missing again
+        self.forget_wanted();
+        true
+    }
+
+    pub fn set_file_priorities(&self, priorities: &[FilePriority]) {
+        for (file, priority) in priorities.iter().enumerate().take(self.files.len()) {
+            self.set_file_priority(file, *priority);
+        }
+    }
+
+    /// Highest priority among the files a piece has data in.
+    fn piece_priority(&self, piece: &Piece, priorities: &[FilePriority]) -> FilePriority {
+        piece
+            .files
+            .clone()
+            .filter(|&f| self.files[f].has_data())
+            .map(|f| priorities[f])
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Missing pieces worth requesting, most wanted first. Some may have
+    /// every block requested already. Only built again after a piece is
+    /// verified or lost or a priority changes, not on every call.
+    pub fn wanted_pieces(&self) -> Arc<Vec<u32>> {
+        let mut cached = self.wanted.lock().unwrap();
+        if let Some(wanted) = &*cached {
+            return Arc::clone(wanted);
+        }
+        let priorities = self.priorities.read().unwrap();
+        let mut wanted = self
+            .pieces
+            .iter()
+            .filter_map(|piece| {
+                let piece = piece.lock().unwrap();
+                if piece.status == PieceStatus::Available {
+                    return None;
+                }
+                let priority = self.piece_priority(&piece, &priorities);
+                (priority != FilePriority::Skip).then_some((priority, piece.number))
+            })
+            .collect::<Vec<(FilePriority, u32)>>();
+        //stable, so within a priority pieces still go in order
+        wanted.sort_by_key(|w| Reverse(w.0));
+        let wanted = Arc::new(wanted.into_iter().map(|(_, number)| number).collect());
+        *cached = Some(Arc::clone(&wanted));
+        wanted
+    }
+
+    /// Called without holding the priorities or a piece, `wanted_pieces`
+    /// takes those after this one.
+    fn forget_wanted(&self) {
+        *self.wanted.lock().unwrap() = None;
+    }
+
+    /// Stops at the first file that can't be created or sized.
+    pub fn preallocate(&self) -> Result<(), Error> {
+        debug!(target: "content", info_hash:% = self.info_hash; "Preallocating files");
+        self.events
+            .preallocaion_start
+            .iter()
+            .for_each(|e| e(self.files.len() as u32));
+        self.fresh.lock().unwrap().clear();
+        let priorities = self.file_priorities();
+        for (file, _) in priorities
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| **p != FilePriority::Skip)
+        {
+            self.preallocate_file(file)?;
+        }
         self.events
             .preallocaion_end
             .iter()
             .for_each(|e| e(self.files.len() as u32));
+        Ok(())
+    }
+
+    fn preallocate_file(&self, file: usize) -> Result<(), Error> {
+        if !self.files[file].has_data() {
+            return Ok(());
+        }
+        if self.storage.file_state(file).length == 0 {
+            self.fresh.lock().unwrap().insert(file);
+        }
+        self.storage
+            .preallocate(file, self.files[file].length as u64)
+            .map_err(|e| self.storage_error(file, e))
+    }
+
+    fn storage_error(&self, file: usize, source: io::Error) -> Error {
+        Error::Storage {
+            path: self.files[file].path.clone(),
+            source,
+        }
+    }
+
+    /// For failures after the torrent started, nobody is there to return
+    /// them to.
+    pub(crate) fn report_error(&self, error: Error) {
+        let target = match error {
+            Error::Tracker(_) => "tracker",
+            _ => "content",
+        };
+        error!(target: target, info_hash:% = self.info_hash; "{}", error);
+        self.events.error.iter().for_each(|e| e(&error));
+        if let Some(kind) = EventKind::from_error(&error) {
+            self.emit(kind);
+        }
+    }
+
+    /// Hashes every piece against what is on disk. Returns false if
+    /// `should_stop` cut it short, pieces that weren't reached stay missing.
+    pub fn check_content_hash(&self, should_stop: &(dyn Fn() -> bool + Sync)) -> bool {
+        debug!(target: "content", info_hash:% = self.info_hash; "Checking files hash");
+        let all = (0..self.pieces.len()).collect::<Vec<usize>>();
+        if !self.check_pieces(&all, should_stop) {
+            return false;
+        }
+        self.report_hash_check();
+        true
     }
 
-    pub fn check_content_hash(&self) {
-        println!("Checking files hash");
+    /// Reads the pieces in file order on this thread and hashes them on a
+    /// few workers. Only a handful of piece buffers exist at once, so memory
+    /// stays flat no matter how big the torrent is.
+    fn check_pieces(&self, pieces: &[usize], should_stop: &(dyn Fn() -> bool + Sync)) -> bool {
+        let workers = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4);
+        let buffers = workers * 2;
+        //nothing to read from a file that wasn't there or was never written to
+        let fresh = self.fresh.lock().unwrap().clone();
+        let present = self
+            .files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| {
+                !f.has_data()
+                    || f.length == 0
+                    || (!fresh.contains(&i) && self.storage.file_state(i).length > 0)
+            })
+            .collect::<Vec<bool>>();
+
+        let total = pieces.len() as u32;
+        let checked = AtomicU32::new(0);
+        let progress = || {
+            let checked = checked.fetch_add(1, Ordering::Relaxed) + 1;
+            self.events
+                .hash_progress
+                .iter()
+                .for_each(|e| e(checked, total));
+        };
 
-        //todo maybe return this threadPool?
-        // let pool = ThreadPool::new(9);
+        let (job_tx, job_rx) = sync_channel::<(usize, Vec<u8>)>(buffers);
+        let job_rx = Mutex::new(job_rx);
+        let (free_tx, free_rx) = channel::<Vec<u8>>();
+        for _ in 0..buffers {
+            free_tx.send(Vec::new()).unwrap();
+        }
 
+        let mut sorted = pieces.to_vec();
+        sorted.sort_unstable();
+        let mut completed = true;
         thread::scope(|s| {
-            let mut handles = vec![];
-            for piece in &self.pieces {
-                let h = s.spawn(move || {
-                    let mut read_buf = Vec::with_capacity(piece.lock().unwrap().size as usize);
-                    let mut piece = piece.lock().unwrap();
-                    let offset = piece.offset;
-                    let files = &piece.files;
-
-                    let mut first = true;
-                    let mut r = 0;
-                    for file in files {
-                        let mut f = OpenOptions::new()
-                            .read(true)
-                            .open(file.0.as_path())
-                            .unwrap();
-
-                        if first {
-                            f.seek(SeekFrom::Start(offset as u64)).expect("seek failed");
-                            first = false;
-                        }
-
-                        r += f
-                            .take(piece.size as u64 - r as u64)
-                            .read_to_end(&mut read_buf)
-                            .unwrap();
-                    }
-                    piece.check_hash(&read_buf);
+            for _ in 0..workers {
+                s.spawn(|| loop {
+                    let job = job_rx.lock().unwrap().recv();
+                    let Ok((index, buf)) = job else {
+                        break;
+                    };
+                    self.pieces[index].lock().unwrap().check_hash(&buf);
+                    progress();
+                    let _ = free_tx.send(buf);
                 });
-                handles.push(h);
             }
-            for handle in handles {
-                let _r = handle.join();
+
+            for index in sorted {
+                if should_stop() {
+                    completed = false;
+                    break;
+                }
+                let mut piece = self.pieces[index].lock().unwrap();
+                if !piece.files.clone().all(|f| present[f]) {
+                    piece.reset();
+                    drop(piece);
+                    progress();
+                    continue;
+                }
+                let mut buf = free_rx.recv().unwrap();
+                read_piece(self.storage(), &self.files, &piece, &mut buf);
+                drop(piece);
+                job_tx.send((index, buf)).unwrap();
             }
+            drop(job_tx);
         });
+        self.forget_wanted();
+        completed
+    }
+
+    fn report_hash_check(&self) {
         let mut has = 0;
         for piece in &self.pieces {
-            match piece.lock().unwrap().status {
-                PieceStatus::Missing => {
-                    print!("\x1b[91m");
-                }
-                PieceStatus::Available => {
-                    has += 1;
-                    print!("\x1b[92m");
-                }
-                PieceStatus::Awaiting(_) => unreachable!(),
+            if piece.lock().unwrap().status == PieceStatus::Available {
+                has += 1;
             }
-            print!("{} ", piece.lock().unwrap().number);
         }
-        println!("\x1b[0m");
+        info!(
+            target: "content",
+            info_hash:% = self.info_hash;
+            "Have {} out of {} pieces", has, self.pieces.len()
+        );
         self.events
             .hash_checked
             .iter()
             .for_each(|e| e(has, self.pieces.len() as u32));
     }
 
-    pub fn add_block(&self, piece_number: usize, offset: usize, block: &[u8]) -> Option<bool> {
-        self.pieces[piece_number]
+    pub fn file_states(&self) -> Vec<FileState> {
+        (0..self.files.len())
+            .map(|f| self.storage.file_state(f))
+            .collect()
+    }
+
+    pub fn resume_data(&self, info_hash: &InfoHash) -> ResumeData {
+        let bitfield = self.get_bitfield();
+        let partial_pieces = self
+            .pieces
+            .iter()
+            .filter_map(|piece| {
+                let piece = piece.lock().unwrap();
+                match &piece.status {
+                    PieceStatus::Awaiting(buf) if piece.block_count > 0 => Some(PartialPiece {
+                        piece: piece.number,
+                        blocks: piece.received_offsets(),
+                        data: buf.to_vec(),
+                    }),
+                    _ => None,
+                }
+            })
+            .collect();
+        //after the bitfield, a piece written in between only makes the file
+        //look changed and get checked again
+        let files = self.file_states();
+
+        ResumeData {
+            info_hash: *info_hash.raw(),
+            destination: self.destination_path.clone(),
+            bitfield,
+            files,
+            priorities: self.file_priorities(),
+            partial_pieces,
+        }
+    }
+
+    /// Takes the piece state from resume data instead of hashing everything.
+    /// Pieces touching a file that changed since the data was saved are
+    /// hashed again. Returns false if `should_stop` cut the check short.
+    pub fn resume(&self, data: &ResumeData, should_stop: &(dyn Fn() -> bool + Sync)) -> bool {
+        debug!(target: "content", info_hash:% = self.info_hash; "Resuming");
+        let current = self.file_states();
+        let changed = current
+            .iter()
+            .enumerate()
+            .map(|(i, state)| data.files.get(i) != Some(state))
+            .collect::<Vec<bool>>();
+
+        let mut to_check = vec![];
+        for (i, piece) in self.pieces.iter().enumerate() {
+            let mut piece = piece.lock().unwrap();
+            if piece.files.clone().any(|f| changed[f]) {
+                to_check.push(i);
+            } else if data.has_piece(i) {
+                piece.status = PieceStatus::Available;
+            }
+        }
+        for partial in &data.partial_pieces {
+            let Some(piece) = self.pieces.get(partial.piece as usize) else {
+                continue;
+            };
+            let mut piece = piece.lock().unwrap();
+            if piece.status != PieceStatus::Missing || partial.data.len() != piece.size as usize {
+                continue;
+            }
+            //more partial pieces than buffers, the rest is downloaded again
+            let Some(mut buf) = self.buffers.acquire(partial.data.len()) else {
+                break;
+            };
+            buf.copy_from_slice(&partial.data);
+            piece.restore(&partial.blocks, buf);
+        }
+        self.forget_wanted();
+
+        if !to_check.is_empty() {
+            debug!(
+                target: "content",
+                info_hash:% = self.info_hash;
+                "Checking {} pieces of changed files", to_check.len()
+            );
+            if !self.check_pieces(&to_check, should_stop) {
+                return false;
+            }
+        }
+        self.report_hash_check();
+        true
+    }
+
+    /// Takes a block `peer` sent from `ip`. `Some` once it completes the
+    /// piece, with whether the hash matched. Blocks that weren't asked from
+    /// this peer or don't fit the piece are refused, the peer is broken or
+    /// malicious. Corrupt data is blamed on the address, ids are made up by
+    /// the peers themselves.
+    /// A piece that can't be written goes to the `error` event and is
+    /// downloaded again.
+    pub fn add_block(
+        &self,
+        piece_number: usize,
+        offset: usize,
+        block: &[u8],
+        peer: &PeerId,
+        ip: IpAddr,
+    ) -> Result<Option<bool>, BlockError> {
+        let piece = self
+            .pieces
+            .get(piece_number)
+            .ok_or(BlockError::UnknownPiece(piece_number))?;
+        //priorities before the piece, the same order wanted_pieces locks them in
+        let priorities = self.priorities.read().unwrap();
+        let done = piece
             .lock()
             .unwrap()
-            .add_block(offset, block)
+            .add_block(offset, block, peer, ip, self, &priorities)?;
+        match done {
+            Some(true) => {
+                self.emit(EventKind::PieceFinished {
+                    piece: piece_number as u32,
+                });
+                let files = piece.lock().unwrap().files.clone();
+                for file in files.filter(|&f| {
+                    self.files[f].has_data()
+                        && self.files[f].length > 0
+                        && priorities[f] != FilePriority::Skip
+                        && self.is_file_complete(f)
+                }) {
+                    self.emit(EventKind::FileCompleted { file });
+                }
+            }
+            Some(false) => self.emit(EventKind::HashFailed {
+                piece: piece_number as u32,
+            }),
+            None => (),
+        }
+        drop(priorities);
+        if done == Some(true) {
+            self.forget_wanted();
+        }
+        Ok(done)
     }
 
+    /// `length` bytes at `offset` into a verified piece, for uploading. `None`
+    /// if the piece isn't available, the range doesn't fit in it or it can't
+    /// be read. Pieces that touch a skipped file are never sent, that part
+    /// of them was not written.
+    pub fn read_block(&self, piece: usize, offset: u32, length: u32) -> Option<Vec<u8>> {
+        let priorities = self.priorities.read().unwrap();
+        let (start, files) = {
+            let piece = self.pieces.get(piece)?.lock().unwrap();
+            if piece.status != PieceStatus::Available
+                || offset as u64 + length as u64 > piece.size as u64
+            {
+                return None;
+            }
+            (piece.offset, piece.files.clone())
+        };
+        if files
+            .clone()
+            .any(|f| self.files[f].has_data() && priorities[f] == FilePriority::Skip)
+        {
+            return None;
+        }
+        drop(priorities);
+        let mut block = vec![0; length as usize];
+        //position in the file the block starts in
+        let mut position = start + offset as usize;
+        let mut filled = 0;
+        for index in files {
+            let file = &self.files[index];
+            if position >= file.length {
+                position -= file.length;
+                continue;
+            }
+            let how_much = std::cmp::min(file.length - position, block.len() - filled);
+            if how_much == 0 {
+                break;
+            }
+            //pad files and symlinks are zeros, as they were when hashed
+            if file.has_data() {
+                let span = &mut block[filled..filled + how_much];
+                self.storage.read(index, position as u64, span).ok()?;
+            }
+            filled += how_much;
+            position = 0;
+        }
+        Some(block)
+    }
+
+    /// Whether every piece the file has data in is available.
+    fn is_file_complete(&self, file: usize) -> bool {
+        let start: usize = self.files[..file].iter().map(|f| f.length).sum();
+        let piece_length = self.pieces[0].lock().unwrap().size as usize;
+        let end = start + self.files[file].length;
+        (start / piece_length..end.div_ceil(piece_length))
+            .all(|p| self.pieces[p].lock().unwrap().status == PieceStatus::Available)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces
+            .iter()
+            .all(|p| p.lock().unwrap().status == PieceStatus::Available)
+    }
+
+    /// Every piece that isn't skipped is available.
+    pub fn is_finished(&self) -> bool {
+        let priorities = self.priorities.read().unwrap();
+        self.pieces.iter().all(|p| {
+            let p = p.lock().unwrap();
+            p.status == PieceStatus::Available
+                || self.piece_priority(&p, &priorities) == FilePriority::Skip
+        })
+    }
+
+    /// Applies what BEP 47 asks for once all data is in place: executable bits
+    /// and symlinks.
+    pub fn finalize_files(&self) {
+        let priorities = self.file_priorities();
+        for (file, _) in priorities
+            .iter()
+            .enumerate()
+            .filter(|(i, p)| **p != FilePriority::Skip && !self.files[*i].attributes.pad)
+        {
+            if let Err(e) = self.storage.finalize(file) {
+                self.report_error(self.storage_error(file, e));
+            }
+        }
+    }
+
+    /// Returns the offset into the first file and the range of files the piece spans.
     pub fn get_piece_files(
         piece: usize,
-        files: &Vec<(PathBuf, usize)>,
+        files: &[ContentFile],
         piece_length: usize,
         length: usize,
-    ) -> (usize, Vec<(PathBuf, usize)>) {
+    ) -> (usize, Range<usize>) {
         let mut l = 0;
         let mut first_file = 0;
         let mut last_file = 0;
         for fnum in 0..files.len() {
-            l += files[fnum].1;
+            l += files[fnum].length;
             if (piece + 1) * piece_length > length {
                 last_file = files.len() - 1;
                 break;
@@ -227,20 +767,19 @@ impl Content {
         }
         l = 0;
         for (fnum, item) in files.iter().enumerate() {
-            l += item.1;
+            l += item.length;
             if piece * piece_length <= l {
                 first_file = fnum;
                 break;
             }
         }
 
-        let first_offset = files[first_file].1 - (l - (piece * piece_length));
-        let files = &files[first_file..=last_file];
-        (first_offset, files.to_vec())
+        let first_offset = files[first_file].length - (l - (piece * piece_length));
+        (first_offset, first_file..last_file + 1)
     }
 
     pub fn get_bitfield(&self) -> Vec<u8> {
-        let size = self.pieces.len() / 8 + if self.pieces.len() % 8 == 0 { 0 } else { 1 };
+        let size = self.pieces.len().div_ceil(8);
         let mut field = vec![0u8; size];
 
         for (i, piece) in self.pieces.iter().enumerate() {
@@ -259,6 +798,16 @@ pub struct ContentEvents {
     pub preallocaion_start: Vec<Box<dyn Fn(u32) + 'static + Send + Sync>>,
     pub preallocaion_end: Vec<Box<dyn Fn(u32) + 'static + Send + Sync>>,
     pub hash_checked: Vec<Box<dyn Fn(u32, u32) + 'static + Send + Sync>>,
+    /// (pieces checked so far, pieces to check), once per piece.
+    pub hash_progress: Vec<Box<dyn Fn(u32, u32) + 'static + Send + Sync>>,
+    /// (address, piece) of a peer banned for sending corrupt data for the piece.
+    pub peer_banned: Vec<Box<dyn Fn(IpAddr, u32) + 'static + Send + Sync>>,
+    /// A seed goal was reached, the torrent stops and then does the action.
+    pub seed_goal_reached: Vec<Box<dyn Fn(SeedGoal, GoalAction) + 'static + Send + Sync>>,
+    /// (from, to) on every change of the torrent's state.
+    pub state_changed: Vec<Box<dyn Fn(TorrentState, TorrentState) + 'static + Send + Sync>>,
+    /// Something failed while the torrent was running, it may keep going.
+    pub error: Vec<ErrorCallback>,
 }
 
 impl ContentEvents {
@@ -267,6 +816,11 @@ impl ContentEvents {
             preallocaion_start: vec![],
             preallocaion_end: vec![],
             hash_checked: vec![],
+            hash_progress: vec![],
+            peer_banned: vec![],
+            seed_goal_reached: vec![],
+            state_changed: vec![],
+            error: vec![],
         }
     }
 }
@@ -288,126 +842,618 @@ impl std::fmt::Debug for ContentEvents {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Reads a piece back from storage. Whatever can't be read (skipped files
+/// that were never created, for one) comes back as zeros and will simply
+/// fail the hash check.
+fn read_piece(storage: &dyn Storage, files: &[ContentFile], piece: &Piece, buf: &mut Vec<u8>) {
+    buf.clear();
+    buf.resize(piece.size as usize, 0);
+    let mut offset = piece.offset;
+    let mut start = 0;
+
+    for index in piece.files.clone() {
+        let file = &files[index];
+        let how_much = std::cmp::min(file.length - offset, buf.len() - start);
+        let span = &mut buf[start..start + how_much];
+        if file.has_data() && how_much > 0 && storage.read(index, offset as u64, span).is_err() {
+            span.fill(0);
+        }
+        start += how_much;
+        offset = 0;
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct Piece {
     pub number: u32,
     pub size: u32,
     offset: usize,
     pub status: PieceStatus,
     hash: [u8; 20],
-    //TODO
-    //make files a slice, I DARE YOU
-    //you will fall into
-    //a liftime of lifitime annotations
-    //hell
-    files: Vec<(PathBuf, usize)>,
+    //indices into Content::files, a slice would have dragged lifetimes everywhere
+    files: Range<usize>,
+    //blocks received
     block_count: u32,
     block_count_goal: u32,
+    //one per block of blocks()
+    block_states: Vec<BlockState>,
+    //(block, sender, hash of what it sent) from attempts that failed the hash check
+    suspects: Vec<(usize, IpAddr, [u8; 20])>,
+    culprits: Vec<IpAddr>,
+}
+
+/// Where a block of a piece that is being downloaded stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockState {
+    Missing,
+    Requested(PeerId),
+    /// In the piece buffer with the address it came from, `None` for blocks
+    /// from resume data.
+    Received(Option<IpAddr>),
+}
+
+/// Why a block was refused by `Content::add_block`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    UnknownPiece(usize),
+    /// Goes past the end of the piece.
+    OutOfRange {
+        piece: u32,
+        offset: usize,
+        length: usize,
+    },
+    /// Doesn't start where a block starts.
+    Misaligned {
+        piece: u32,
+        offset: usize,
+    },
+    WrongLength {
+        piece: u32,
+        offset: usize,
+        expected: u32,
+        actual: usize,
+    },
+    /// Not asked from this peer.
+    Unrequested {
+        piece: u32,
+        offset: usize,
+    },
+    Duplicate {
+        piece: u32,
+        offset: usize,
+    },
+}
+
+impl BlockError {
+    /// Whether the block can't be right whatever was asked, rather than one
+    /// that arrived late or twice. Blocks already on the way when a peer
+    /// chokes still come in, those aren't the peer's fault.
+    pub fn is_violation(&self) -> bool {
+        !matches!(
+            self,
+            BlockError::Unrequested { .. } | BlockError::Duplicate { .. }
+        )
+    }
 }
 
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::UnknownPiece(piece) => write!(f, "there is no piece {}", piece),
+            BlockError::OutOfRange {
+                piece,
+                offset,
+                length,
+            } => write!(
+                f,
+                "block of {} bytes at {} is past the end of piece {}",
+                length, offset, piece
+            ),
+            BlockError::Misaligned { piece, offset } => {
+                write!(f, "no block of piece {} starts at {}", piece, offset)
+            }
+            BlockError::WrongLength {
+                piece,
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "block at {} of piece {} is {} bytes, not {}",
+                offset, piece, actual, expected
+            ),
+            BlockError::Unrequested { piece, offset } => {
+                write!(f, "block at {} of piece {} wasn't requested", offset, piece)
+            }
+            BlockError::Duplicate { piece, offset } => {
+                write!(
+                    f,
+                    "block at {} of piece {} was already received",
+                    offset, piece
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
 impl Piece {
     fn new(
         number: u32,
         size: u32,
-        block_count_goal: u32,
         offset: usize,
-        files: Vec<(PathBuf, usize)>,
+        files: Range<usize>,
         hash: [u8; 20],
     ) -> Piece {
-        Piece {
+        let mut piece = Piece {
             number,
             size,
             hash,
             status: PieceStatus::Missing,
-            files: files.to_vec(),
+            files,
             offset,
-            block_count_goal,
+            block_count_goal: 0,
             block_count: 0,
-        }
+            block_states: vec![],
+            suspects: vec![],
+            culprits: vec![],
+        };
+        //pieces shorter than a block still have one
+        piece.block_count_goal = piece.blocks().len() as u32;
+        piece.block_states = vec![BlockState::Missing; piece.block_count_goal as usize];
+        piece
     }
 
-    fn add_block(&mut self, offset: usize, block: &[u8]) -> Option<bool> {
-        self.block_count += 1;
+    /// (offset, length) of every block of the piece. The tail of a short
+    /// piece is split into power of two blocks.
+    pub fn blocks(&self) -> Vec<(u32, u32)> {
+        let mut blocks = vec![];
+        let mut offset: u32 = 0;
+        let mut left = self.size;
+        while left > 0 {
+            let block_size = if left < BLOCK_SIZE {
+                //bitwise magic! this finds the leftmost bit of what is left
+                1 << (31 - left.leading_zeros())
+            } else {
+                BLOCK_SIZE
+            };
+            blocks.push((offset, block_size));
+            left -= block_size;
+            offset += block_size;
+        }
+        blocks
+    }
 
-        let buf = match &self.status {
-            PieceStatus::Missing => vec![0u8; self.size.try_into().unwrap()],
-            PieceStatus::Awaiting(b) => b.to_vec(),
-            PieceStatus::Available => panic!("This piece is already available"),
-        };
+    /// Blocks neither received nor requested from anyone.
+    pub fn missing_blocks(&self) -> Vec<(u32, u32)> {
+        self.blocks()
+            .into_iter()
+            .zip(&self.block_states)
+            .filter(|(_, state)| **state == BlockState::Missing)
+            .map(|(block, _)| block)
+            .collect()
+    }
 
-        let new_buf = [&buf[..offset], block, &buf[offset + block.len()..]].concat();
+    fn received_offsets(&self) -> Vec<u32> {
+        self.blocks()
+            .into_iter()
+            .zip(&self.block_states)
+            .filter(|(_, state)| matches!(state, BlockState::Received(_)))
+            .map(|((offset, _), _)| offset)
+            .collect()
+    }
 
-        self.status = PieceStatus::Awaiting(new_buf);
+    fn request(&mut self, peer: &PeerId, buffers: &Arc<BufferPool>) -> Option<Vec<(u32, u32)>> {
+        let blocks = self.missing_blocks();
+        if blocks.is_empty() {
+            return None;
+        }
+        match self.status {
+            PieceStatus::Missing => {
+                self.status = PieceStatus::Awaiting(buffers.acquire(self.size as usize)?)
+            }
+            PieceStatus::Awaiting(_) => (),
+            PieceStatus::Available => return None,
+        }
+        for state in &mut self.block_states {
+            if *state == BlockState::Missing {
+                *state = BlockState::Requested(*peer);
+            }
+        }
+        Some(blocks)
+    }
 
-        if self.block_count == self.block_count_goal {
-            return Some(self.write());
+    fn cancel(&mut self, peer: &PeerId) {
+        for state in &mut self.block_states {
+            if *state == BlockState::Requested(*peer) {
+                *state = BlockState::Missing;
+            }
+        }
+        //nothing received or on the way, the buffer can go
+        if self.block_states.iter().all(|s| *s == BlockState::Missing)
+            && matches!(self.status, PieceStatus::Awaiting(_))
+        {
+            self.status = PieceStatus::Missing;
         }
-        None
     }
 
-    fn write(&mut self) -> bool {
-        //if whole piece is downloaded
-        if let PieceStatus::Awaiting(mut buffer) = self.status.clone() {
-            let mut offset = self.offset;
+    fn add_block(
+        &mut self,
+        offset: usize,
+        block: &[u8],
+        peer: &PeerId,
+        ip: IpAddr,
+        content: &Content,
+        priorities: &[FilePriority],
+    ) -> Result<Option<bool>, BlockError> {
+        let piece = self.number;
+        if offset + block.len() > self.size as usize {
+            return Err(BlockError::OutOfRange {
+                piece,
+                offset,
+                length: block.len(),
+            });
+        }
+        let blocks = self.blocks();
+        let index = blocks
+            .iter()
+            .position(|(o, _)| *o as usize == offset)
+            .ok_or(BlockError::Misaligned { piece, offset })?;
+        if blocks[index].1 as usize != block.len() {
+            return Err(BlockError::WrongLength {
+                piece,
+                offset,
+                expected: blocks[index].1,
+                actual: block.len(),
+            });
+        }
+        match self.block_states[index] {
+            BlockState::Requested(from) if from == *peer => (),
+            BlockState::Received(_) => return Err(BlockError::Duplicate { piece, offset }),
+            _ => return Err(BlockError::Unrequested { piece, offset }),
+        }
+        //requested blocks always have a buffer to go in
+        let PieceStatus::Awaiting(buf) = &mut self.status else {
+            return Err(BlockError::Unrequested { piece, offset });
+        };
+        buf[offset..offset + block.len()].copy_from_slice(block);
+        self.block_states[index] = BlockState::Received(Some(ip));
+        self.block_count += 1;
 
-            if !self.check_hash(&buffer) {
-                return false;
-            }
+        if self.block_count < self.block_count_goal {
+            return Ok(None);
+        }
+        //whole piece is downloaded
+        let status = std::mem::replace(&mut self.status, PieceStatus::Missing);
+        let PieceStatus::Awaiting(buffer) = status else {
+            return Err(BlockError::Unrequested { piece, offset });
+        };
+        Ok(self.write(buffer, content, priorities))
+    }
 
-            let mut prev_written_bytes = 0;
-            for file in &self.files {
-                let mut f = OpenOptions::new()
-                    .write(true)
-                    .open(file.0.as_path())
-                    .unwrap();
+    /// Whether the hash matched, `None` if it did but writing failed and
+    /// the piece has to be downloaded again.
+    fn write(
+        &mut self,
+        buffer: PieceBuffer,
+        content: &Content,
+        priorities: &[FilePriority],
+    ) -> Option<bool> {
+        let mut offset = self.offset;
 
-                f.seek(SeekFrom::Start(offset as u64)).expect("seek failed");
+        //check_hash forgets who sent what
+        let senders = self
+            .block_states
+            .iter()
+            .map(|state| match state {
+                BlockState::Received(peer) => *peer,
+                _ => None,
+            })
+            .collect::<Vec<Option<IpAddr>>>();
+        if !self.check_hash(&buffer) {
+            self.blame(&buffer, &senders);
+            return Some(false);
+        }
+        if !self.suspects.is_empty() {
+            self.convict(&buffer);
+        }
 
-                let how_much =
-                    std::cmp::min(file.1 - offset, self.size as usize - prev_written_bytes);
-                let written = f.write(&buffer[..how_much]);
-                match written {
-                    Ok(count) => {
-                        prev_written_bytes = count;
-                        buffer.drain(..count);
-                        offset = 0;
-                    }
-                    Err(e) => {
-                        println!("Write failed\n{:?}", e);
-                    }
+        let mut written = 0;
+        for index in self.files.clone() {
+            let file = &content.files[index];
+            let how_much = std::cmp::min(file.length - offset, buffer.len() - written);
+            //a piece on the edge of a skipped file must not create it
+            //or leave its part of the piece in there
+            if file.has_data() && priorities[index] != FilePriority::Skip {
+                let r = content.storage.write(
+                    index,
+                    offset as u64,
+                    &buffer[written..written + how_much],
+                );
+                if let Err(e) = r {
+                    content.report_error(content.storage_error(index, e));
+                    self.reset();
+                    return None;
                 }
             }
-            true
-        } else {
-            panic!("Trying to write a piece with no buffer");
+            written += how_much;
+            offset = 0;
         }
+        Some(true)
     }
 
     fn check_hash(&mut self, buffer: &[u8]) -> bool {
         let mut hasher = Sha1::new();
         hasher.update(buffer);
         let hexes = hasher.finalize();
-        let hexes: [u8; 20] = hexes.try_into().expect("Wrong length checking hash");
+        let hexes: [u8; 20] = hexes.into();
         if hexes == self.hash {
             self.status = PieceStatus::Available;
         } else {
-            self.status = PieceStatus::Missing;
-            self.block_count = 0;
+            self.reset();
         };
         hexes == self.hash
     }
 
-    pub fn make_awaiting(&mut self) {
-        if self.status != PieceStatus::Missing {
-            return;
-        };
-        self.status = PieceStatus::Awaiting(vec![0u8; self.size.try_into().unwrap()]);
+    /// After a failed hash check. A piece that came from one address alone
+    /// convicts it, otherwise who sent what is kept for `convict`.
+    fn blame(&mut self, buffer: &[u8], senders: &[Option<IpAddr>]) {
+        if let Some(Some(first)) = senders.first() {
+            if senders.iter().all(|s| *s == Some(*first)) {
+                self.add_culprit(*first);
+                return;
+            }
+        }
+        for (index, (offset, length)) in self.blocks().into_iter().enumerate() {
+            if let Some(peer) = senders[index] {
+                let block = &buffer[offset as usize..(offset + length) as usize];
+                self.suspects
+                    .push((index, peer, Sha1::digest(block).into()));
+            }
+        }
+    }
+
+    /// After the piece passed, whoever sent a block that differs from the
+    /// good one sent corrupt data.
+    fn convict(&mut self, buffer: &[u8]) {
+        let blocks = self.blocks();
+        for (index, peer, hash) in std::mem::take(&mut self.suspects) {
+            let (offset, length) = blocks[index];
+            let block = &buffer[offset as usize..(offset + length) as usize];
+            let good: [u8; 20] = Sha1::digest(block).into();
+            if good != hash {
+                self.add_culprit(peer);
+            }
+        }
+    }
+
+    fn add_culprit(&mut self, peer: IpAddr) {
+        if !self.culprits.contains(&peer) {
+            self.culprits.push(peer);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.status = PieceStatus::Missing;
+        self.block_count = 0;
+        self.block_states.fill(BlockState::Missing);
+    }
+
+    /// Puts back a partly downloaded piece, nobody is asked for the rest yet.
+    fn restore(&mut self, blocks: &[u32], buf: PieceBuffer) {
+        self.block_states = self
+            .blocks()
+            .iter()
+            .map(|(offset, _)| match blocks.contains(offset) {
+                true => BlockState::Received(None),
+                false => BlockState::Missing,
+            })
+            .collect();
+        self.block_count = self
+            .block_states
+            .iter()
+            .filter(|s| matches!(s, BlockState::Received(_)))
+            .count() as u32;
+        self.status = PieceStatus::Awaiting(buf);
+    }
+
+    /// Has blocks nobody is asked for yet.
+    pub fn is_wanted(&self) -> bool {
+        match self.status {
+            PieceStatus::Missing => true,
+            PieceStatus::Awaiting(_) => self.block_states.contains(&BlockState::Missing),
+            PieceStatus::Available => false,
+        }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum PieceStatus {
     Missing,
     Available,
-    Awaiting(Vec<u8>),
+    Awaiting(PieceBuffer),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::tf::fixture::TestTorrent;
+
+    /// A single file torrent of `data`, kept in memory.
+    fn content(data: &[u8], piece_length: usize) -> Content {
+        content_of(&TestTorrent::new(data, piece_length))
+    }
+
+    fn content_of(torrent: &TestTorrent) -> Content {
+        let tf = torrent.parse().unwrap();
+        let mut content = Content::new(&tf, Some("/nonexistent".into())).unwrap();
+        content.set_storage(Box::new(MemoryStorage::new(content.files().len())));
+        content.preallocate().unwrap();
+        content
+    }
+
+    /// Requests every piece and hands it the blocks of `data`.
+    fn download(content: &Content, data: &[u8]) {
+        let (peer, ip) = ([1; 20], IpAddr::from([10, 0, 0, 1]));
+        for piece in 0..content.pieces.len() {
+            let start = piece * content.pieces[0].lock().unwrap().size as usize;
+            for (offset, length) in content.request_piece(piece, &peer).unwrap() {
+                let block = &data[start + offset as usize..][..length as usize];
+                let _ = content.add_block(piece, offset as usize, block, &peer, ip);
+            }
+        }
+    }
+
+    #[test]
+    fn pieces_shorter_than_a_block_download() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let content = content(&data, 4096);
+        let (peer, ip) = ([1; 20], IpAddr::from([10, 0, 0, 1]));
+        for piece in 0..content.pieces.len() {
+            let blocks = content.request_piece(piece, &peer).unwrap();
+            assert!(!blocks.is_empty());
+            let mut done = None;
+            for (offset, length) in blocks {
+                let start = piece * 4096 + offset as usize;
+                let block = &data[start..start + length as usize];
+                done = content
+                    .add_block(piece, offset as usize, block, &peer, ip)
+                    .unwrap();
+            }
+            assert_eq!(done, Some(true));
+        }
+        assert!(content.is_complete());
+    }
+
+    #[test]
+    fn fresh_files_are_not_hashed() {
+        //zeros would pass the check if the new file were read
+        let data = vec![0; 40_000];
+        let content = content(&data, 16384);
+        assert!(content.check_content_hash(&|| false));
+        assert!(content
+            .pieces
+            .iter()
+            .all(|p| p.lock().unwrap().status == PieceStatus::Missing));
+
+        //allocated by an earlier run, it is read this time
+        content.preallocate().unwrap();
+        assert!(content.check_content_hash(&|| false));
+        assert!(content.is_complete());
+    }
+
+    #[test]
+    fn priorities_of_missing_files_are_refused() {
+        let content = content(&[1; 100], 16384);
+        assert!(content.set_file_priority(0, FilePriority::High));
+        assert!(!content.set_file_priority(1, FilePriority::Skip));
+        assert_eq!(content.file_priorities(), vec![FilePriority::High]);
+    }
+
+    #[test]
+    fn verified_blocks_are_read_back() {
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+        let torrent = TestTorrent::new(&data, 16384).with_files(&[
+            (10_000, "a"),
+            (0, "empty"),
+            (30_000, "b"),
+        ]);
+        let content = content_of(&torrent);
+        assert!(content.read_block(0, 0, 16384).is_none());
+        download(&content, &data);
+        assert!(content.is_complete());
+
+        //across the files
+        assert_eq!(content.read_block(0, 9000, 2000).unwrap(), &data[9000..11000]);
+        assert_eq!(content.read_block(2, 0, 7232).unwrap(), &data[32768..]);
+        assert!(content.read_block(2, 0, 7233).is_none());
+        assert!(content.read_block(2, u32::MAX, 2).is_none());
+        assert!(content.read_block(3, 0, 1).is_none());
+
+        //part of it was never written
+        content.set_file_priority(0, FilePriority::Skip);
+        assert!(content.read_block(0, 12000, 100).is_none());
+        assert!(content.read_block(1, 0, 100).is_some());
+    }
+
+    #[test]
+    fn wanted_pieces_follow_priorities_and_downloads() {
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+        let torrent = TestTorrent::new(&data, 16384).with_files(&[(30_000, "a"), (10_000, "b")]);
+        let content = content_of(&torrent);
+        assert_eq!(*content.wanted_pieces(), vec![0, 1, 2]);
+        content.set_file_priority(0, FilePriority::Skip);
+        assert_eq!(*content.wanted_pieces(), vec![1, 2]);
+        content.set_file_priority(1, FilePriority::Skip);
+        assert!(content.wanted_pieces().is_empty());
+        content.set_file_priority(0, FilePriority::High);
+        assert_eq!(*content.wanted_pieces(), vec![0, 1]);
+
+        let (peer, ip) = ([1; 20], IpAddr::from([10, 0, 0, 1]));
+        for (offset, length) in content.request_piece(0, &peer).unwrap() {
+            let block = &data[offset as usize..][..length as usize];
+            let _ = content.add_block(0, offset as usize, block, &peer, ip);
+        }
+        assert_eq!(*content.wanted_pieces(), vec![1]);
+    }
+
+    #[test]
+    fn late_blocks_are_not_violations() {
+        let data = vec![7; 32768];
+        let content = content(&data, 32768);
+        let (peer, ip) = ([1; 20], IpAddr::from([10, 0, 0, 1]));
+        content.request_piece(0, &peer).unwrap();
+        assert_eq!(content.add_block(0, 0, &data[..16384], &peer, ip), Ok(None));
+        let duplicate = content.add_block(0, 0, &data[..16384], &peer, ip).unwrap_err();
+        assert!(!duplicate.is_violation());
+        //choked, the second block was already on its way
+        content.cancel_piece(0, &peer);
+        let late = content
+            .add_block(0, 16384, &data[16384..], &peer, ip)
+            .unwrap_err();
+        assert!(!late.is_violation());
+
+        //misaligned, too short, past the end, no such piece
+        for (offset, length, piece) in [
+            (100, 16384, 0),
+            (16384, 100, 0),
+            (32768, 16384, 0),
+            (0, 16384, 1),
+        ] {
+            let block = vec![7; length];
+            let e = content
+                .add_block(piece, offset, &block, &peer, ip)
+                .unwrap_err();
+            assert!(e.is_violation(), "{}", e);
+        }
+    }
+
+    #[test]
+    fn corrupt_data_is_blamed_on_the_address() {
+        let data: Vec<u8> = (0..32768u32).map(|i| (i % 251) as u8).collect();
+        let content = content(&data, 32768);
+        let (good, good_ip) = ([1; 20], IpAddr::from([10, 0, 0, 1]));
+        let (bad, bad_ip) = ([2; 20], IpAddr::from([10, 0, 0, 2]));
+
+        //the first block from one, the second from the other
+        assert_eq!(content.request_piece(0, &good).unwrap().len(), 2);
+        let first = content.add_block(0, 0, &data[..16384], &good, good_ip);
+        assert_eq!(first, Ok(None));
+        content.cancel_piece(0, &good);
+        assert_eq!(content.request_piece(0, &bad).unwrap(), vec![(16384, 16384)]);
+        let done = content.add_block(0, 16384, &[0; 16384], &bad, bad_ip);
+        assert_eq!(done, Ok(Some(false)));
+        //two senders, nobody is convicted yet
+        assert!(content.take_corrupt_peers(0).is_empty());
+
+        //the bad peer is gone by the time the piece passes
+        for (offset, length) in content.request_piece(0, &good).unwrap() {
+            let block = &data[offset as usize..(offset + length) as usize];
+            let _ = content.add_block(0, offset as usize, block, &good, good_ip);
+        }
+        assert!(content.is_complete());
+        assert_eq!(content.take_corrupt_peers(0), vec![bad_ip]);
+    }
 }
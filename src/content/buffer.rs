@@ -0,0 +1,107 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// Hands out the buffers pieces are assembled in. Only `limit` of them can
+/// be out at once, so the number of pieces in flight caps the memory used.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Mutex<Buffers>,
+}
+
+#[derive(Debug)]
+struct Buffers {
+    //returned buffers keep their allocation for the next piece
+    free: Vec<Vec<u8>>,
+    in_use: usize,
+    limit: usize,
+}
+
+/// A zeroed buffer from a `BufferPool`, it goes back when dropped.
+pub struct PieceBuffer {
+    data: Vec<u8>,
+    pool: Arc<BufferPool>,
+}
+
+impl BufferPool {
+    pub fn new(limit: usize) -> Arc<BufferPool> {
+        Arc::new(BufferPool {
+            buffers: Mutex::new(Buffers {
+                free: vec![],
+                in_use: 0,
+                limit: limit.max(1),
+            }),
+        })
+    }
+
+    /// `None` while `limit` buffers are out.
+    pub fn acquire(self: &Arc<Self>, size: usize) -> Option<PieceBuffer> {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.in_use >= buffers.limit {
+            return None;
+        }
+        buffers.in_use += 1;
+        let mut data = buffers.free.pop().unwrap_or_default();
+        drop(buffers);
+        data.clear();
+        data.resize(size, 0);
+        Some(PieceBuffer {
+            data,
+            pool: Arc::clone(self),
+        })
+    }
+
+    /// Buffers that are already out stay out, a lower limit only stops new ones.
+    pub fn set_limit(&self, limit: usize) {
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers.limit = limit.max(1);
+        let keep = buffers.limit;
+        buffers.free.truncate(keep);
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.buffers.lock().unwrap().in_use
+    }
+
+    fn release(&self, data: Vec<u8>) {
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers.in_use -= 1;
+        if buffers.free.len() + buffers.in_use < buffers.limit {
+            buffers.free.push(data);
+        }
+    }
+}
+
+impl Drop for PieceBuffer {
+    fn drop(&mut self) {
+        self.pool.release(std::mem::take(&mut self.data));
+    }
+}
+
+impl Deref for PieceBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for PieceBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl PartialEq for PieceBuffer {
+    fn eq(&self, other: &Self) -> bool {
+        self.data == other.data
+    }
+}
+
+impl Eq for PieceBuffer {}
+
+impl fmt::Debug for PieceBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PieceBuffer({} bytes)", self.data.len())
+    }
+}
@@ -0,0 +1,60 @@
+//! The error type of the whole crate.
+use crate::{MetainfoError, TrackerError};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading the torrent file.
+    Io(io::Error),
+    Metainfo(MetainfoError),
+    Tracker(TrackerError),
+    /// Creating, writing or closing one of the downloaded files.
+    Storage { path: PathBuf, source: io::Error },
+    /// No download folder was given and the system has none, or it isn't
+    /// valid UTF-8.
+    NoDownloadDir,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Metainfo(e) => write!(f, "invalid torrent file: {}", e),
+            Error::Tracker(e) => write!(f, "tracker: {}", e),
+            Error::Storage { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::NoDownloadDir => write!(f, "no download folder"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Metainfo(e) => Some(e),
+            Error::Tracker(e) => Some(e),
+            Error::Storage { source, .. } => Some(source),
+            Error::NoDownloadDir => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<MetainfoError> for Error {
+    fn from(e: MetainfoError) -> Self {
+        Error::Metainfo(e)
+    }
+}
+
+impl From<TrackerError> for Error {
+    fn from(e: TrackerError) -> Self {
+        Error::Tracker(e)
+    }
+}
@@ -0,0 +1,133 @@
+//! What torrents tell the application, as typed events over channels.
+//!
+//! `Torrent::subscribe` gets the events of one torrent, `Session::subscribe`
+//! those of every torrent in the session. A subscriber that drops its
+//! receiver is forgotten on the next event.
+use crate::content::PeerId;
+use crate::{Error, TorrentState};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub info_hash: [u8; 20],
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    /// Added to a session.
+    TorrentAdded,
+    StateChanged {
+        from: TorrentState,
+        to: TorrentState,
+    },
+    /// Downloaded, hash checked and written.
+    PieceFinished { piece: u32 },
+    /// Downloaded but corrupt, it is downloaded again.
+    HashFailed { piece: u32 },
+    /// `client` is guessed from the id.
+    PeerConnected {
+        addr: SocketAddr,
+        id: PeerId,
+        client: String,
+    },
+    /// `error` is what broke the connection, `None` if it was closed on
+    /// purpose.
+    PeerDisconnected {
+        addr: SocketAddr,
+        id: PeerId,
+        error: Option<String>,
+    },
+    /// Banned for corrupt data or too many protocol violations.
+    PeerBanned { ip: IpAddr },
+    TrackerReply {
+        url: String,
+        peers: usize,
+        interval: Duration,
+    },
+    TrackerError { url: String, error: String },
+    /// Every piece of the file is there, an index into `Content::files`.
+    FileCompleted { file: usize },
+    /// Everything wanted is downloaded.
+    TorrentFinished,
+    /// Allocating, writing or finalizing a file failed.
+    StorageError { path: PathBuf, error: String },
+}
+
+impl EventKind {
+    /// The event an error that happened while running is reported as.
+    /// The other errors only come up before a torrent runs.
+    pub(crate) fn from_error(error: &Error) -> Option<EventKind> {
+        match error {
+            Error::Storage { path, source } => Some(EventKind::StorageError {
+                path: path.clone(),
+                error: source.to_string(),
+            }),
+            Error::Tracker(e) => Some(EventKind::TrackerError {
+                url: e.url().to_string(),
+                error: e.to_string(),
+            }),
+            Error::Io(_) | Error::Metainfo(_) | Error::NoDownloadDir => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Subscribers(Mutex<Vec<Sender<Event>>>);
+
+impl Subscribers {
+    pub(crate) fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = channel();
+        self.0.lock().unwrap().push(tx);
+        rx
+    }
+
+    fn send(&self, event: &Event) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
+
+/// Where the events of one torrent go: its own subscribers and those of
+/// the session it is in.
+#[derive(Debug)]
+pub(crate) struct EventStream {
+    info_hash: [u8; 20],
+    subscribers: Subscribers,
+    session: Option<Arc<Subscribers>>,
+}
+
+impl EventStream {
+    pub(crate) fn new(info_hash: [u8; 20]) -> EventStream {
+        EventStream {
+            info_hash,
+            subscribers: Subscribers::default(),
+            session: None,
+        }
+    }
+
+    pub(crate) fn subscribe(&self) -> Receiver<Event> {
+        self.subscribers.subscribe()
+    }
+
+    pub(crate) fn set_session(&mut self, session: Arc<Subscribers>) {
+        self.session = Some(session);
+    }
+
+    pub(crate) fn emit(&self, kind: EventKind) {
+        let event = Event {
+            info_hash: self.info_hash,
+            kind,
+        };
+        self.subscribers.send(&event);
+        if let Some(session) = &self.session {
+            session.send(&event);
+        }
+    }
+}
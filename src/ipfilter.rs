@@ -0,0 +1,319 @@
+//! Address ranges peers must never be connected to.
+//!
+//! Reads eMule `ipfilter.dat`, PeerGuardian `p2p` and plain CIDR lists, a
+//! file can mix them. Ranges are merged and kept sorted so a lookup is a
+//! binary search.
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// ipfilter.dat entries with an access level above this are allowed.
+const MAX_BLOCKED_LEVEL: u32 = 127;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    //sorted, non overlapping, inclusive
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+    rejected_lines: usize,
+}
+
+/// Where the address of a peer came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    Incoming,
+    Pex,
+    Dht,
+}
+
+/// Connections refused because of the filter, by where the peer came from.
+#[derive(Debug, Default)]
+pub struct BlockedCounts {
+    tracker: AtomicU64,
+    incoming: AtomicU64,
+    pex: AtomicU64,
+    dht: AtomicU64,
+}
+
+impl IpFilter {
+    /// A filter that blocks nothing.
+    pub fn new() -> IpFilter {
+        IpFilter::default()
+    }
+
+    pub fn load(path: &Path) -> io::Result<IpFilter> {
+        let raw = fs::read(path)?;
+        Ok(IpFilter::parse(&String::from_utf8_lossy(&raw)))
+    }
+
+    /// Lines that are none of the known formats are skipped and counted
+    /// in `rejected_lines`.
+    pub fn parse(text: &str) -> IpFilter {
+        let mut filter = IpFilter::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_line(line) {
+                Some(Some((start, end))) => filter.push(start, end),
+                //a range that is explicitly allowed
+                Some(None) => (),
+                None => filter.rejected_lines += 1,
+            }
+        }
+        filter.normalize();
+        filter
+    }
+
+    /// Blocks `start..=end`, both have to be the same family.
+    pub fn add_range(&mut self, start: IpAddr, end: IpAddr) {
+        self.push(start, end);
+        self.normalize();
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => contains(&self.v6, u128::from(ip)),
+        }
+    }
+
+    /// Number of merged ranges.
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn rejected_lines(&self) -> usize {
+        self.rejected_lines
+    }
+
+    fn push(&mut self, start: IpAddr, end: IpAddr) {
+        match (start.to_canonical(), end.to_canonical()) {
+            (IpAddr::V4(start), IpAddr::V4(end)) => {
+                let (start, end) = (u32::from(start), u32::from(end));
+                self.v4.push((start.min(end), start.max(end)));
+            }
+            (IpAddr::V6(start), IpAddr::V6(end)) => {
+                let (start, end) = (u128::from(start), u128::from(end));
+                self.v6.push((start.min(end), start.max(end)));
+            }
+            _ => self.rejected_lines += 1,
+        }
+    }
+
+    fn normalize(&mut self) {
+        merge(&mut self.v4, |x| x.checked_add(1));
+        merge(&mut self.v6, |x| x.checked_add(1));
+    }
+}
+
+impl BlockedCounts {
+    pub fn get(&self, source: PeerSource) -> u64 {
+        self.counter(source).load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        [
+            PeerSource::Tracker,
+            PeerSource::Incoming,
+            PeerSource::Pex,
+            PeerSource::Dht,
+        ]
+        .into_iter()
+        .map(|source| self.get(source))
+        .sum()
+    }
+
+    pub(crate) fn add(&self, source: PeerSource) {
+        self.counter(source).fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn counter(&self, source: PeerSource) -> &AtomicU64 {
+        match source {
+            PeerSource::Tracker => &self.tracker,
+            PeerSource::Incoming => &self.incoming,
+            PeerSource::Pex => &self.pex,
+            PeerSource::Dht => &self.dht,
+        }
+    }
+}
+
+/// Sorts ranges and joins the ones that overlap or touch.
+fn merge<T: Ord + Copy>(ranges: &mut Vec<(T, T)>, next: impl Fn(T) -> Option<T>) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if next(last.1).is_none_or(|after| start <= after) => {
+                last.1 = last.1.max(end);
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    //first range that starts after ip, the one before it is the only candidate
+    let i = ranges.partition_point(|(start, _)| *start <= ip);
+    i > 0 && ranges[i - 1].1 >= ip
+}
+
+/// `Some(None)` for a line that parses but doesn't block anything.
+fn parse_line(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    //descriptions may have slashes too, only a line that is nothing but a
+    //prefix is CIDR
+    if !line.contains(',') {
+        if let Some((ip, bits)) = line.split_once('/') {
+            if let Some(range) = parse_cidr(ip.trim(), bits.trim()) {
+                return Some(Some(range));
+            }
+        }
+    }
+    //ipfilter.dat: first - last , access , description
+    let fields = line.split(',').map(str::trim).collect::<Vec<&str>>();
+    if fields.len() >= 2 {
+        if let (Some(range), Ok(level)) = (parse_range(fields[0]), fields[1].parse::<u32>()) {
+            return Some((level <= MAX_BLOCKED_LEVEL).then_some(range));
+        }
+    }
+    //p2p: description:first-last, the description may have colons of its own
+    if let Some((_, range)) = line.rsplit_once(':') {
+        if let Some(range) = parse_range(range) {
+            return Some(Some(range));
+        }
+    }
+    //a bare address or range
+    parse_range(line).map(Some)
+}
+
+fn parse_range(range: &str) -> Option<(IpAddr, IpAddr)> {
+    match range.split_once('-') {
+        Some((start, end)) => Some((parse_ip(start.trim())?, parse_ip(end.trim())?)),
+        None => parse_ip(range.trim()).map(|ip| (ip, ip)),
+    }
+}
+
+/// Also takes the zero padded addresses ipfilter.dat is full of, which
+/// the standard parser refuses.
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    if ip.contains(':') {
+        return ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    let mut octets = [0u8; 4];
+    let mut parts = ip.split('.');
+    for octet in &mut octets {
+        let part = parts.next()?;
+        if part.is_empty() || part.len() > 3 || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        *octet = part.parse().ok()?;
+    }
+    match parts.next() {
+        Some(_) => None,
+        None => Some(IpAddr::V4(Ipv4Addr::from(octets))),
+    }
+}
+
+fn parse_cidr(ip: &str, bits: &str) -> Option<(IpAddr, IpAddr)> {
+    let bits = bits.parse::<u32>().ok()?;
+    match parse_ip(ip)? {
+        IpAddr::V4(ip) if bits <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+            let start = u32::from(ip) & mask;
+            Some((IpAddr::V4(start.into()), IpAddr::V4((start | !mask).into())))
+        }
+        IpAddr::V6(ip) if bits <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+            let start = u128::from(ip) & mask;
+            Some((IpAddr::V6(start.into()), IpAddr::V6((start | !mask).into())))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(ip: [u8; 4]) -> IpAddr {
+        IpAddr::from(ip)
+    }
+
+    #[test]
+    fn zero_padded_ipfilter_dat() {
+        let filter = IpFilter::parse("001.002.003.000 - 001.002.003.255 , 000 , Foo\n");
+        assert!(filter.is_blocked(v4([1, 2, 3, 4])));
+        assert!(!filter.is_blocked(v4([1, 2, 4, 0])));
+        assert_eq!(filter.rejected_lines(), 0);
+    }
+
+    #[test]
+    fn allowed_access_level_blocks_nothing() {
+        let filter = IpFilter::parse("1.2.3.0 - 1.2.3.255 , 200 , Allowed");
+        assert!(filter.is_empty());
+        assert_eq!(filter.rejected_lines(), 0);
+    }
+
+    #[test]
+    fn cidr() {
+        let filter = IpFilter::parse("10.0.0.0/8\n2001:db8::/32");
+        assert!(filter.is_blocked(v4([10, 255, 0, 1])));
+        assert!(!filter.is_blocked(v4([11, 0, 0, 0])));
+        assert!(filter.is_blocked("2001:db8::1".parse().unwrap()));
+        assert!(!filter.is_blocked("2001:db9::1".parse().unwrap()));
+        assert!(IpFilter::parse("10.0.0.0/33").is_empty());
+    }
+
+    #[test]
+    fn p2p() {
+        let filter = IpFilter::parse("Some Corp: Inc:1.2.3.0-1.2.3.255");
+        assert!(filter.is_blocked(v4([1, 2, 3, 9])));
+        assert_eq!(filter.rejected_lines(), 0);
+    }
+
+    #[test]
+    fn slashes_in_descriptions() {
+        let filter = IpFilter::parse(
+            "1.2.3.0 - 1.2.3.255 , 000 , Foo I/O Ltd\nAS123/Corp:5.6.7.0-5.6.7.255",
+        );
+        assert_eq!(filter.rejected_lines(), 0);
+        assert!(filter.is_blocked(v4([1, 2, 3, 4])));
+        assert!(filter.is_blocked(v4([5, 6, 7, 8])));
+    }
+
+    #[test]
+    fn malformed_lines_are_counted() {
+        let filter = IpFilter::parse(
+            "# comment\n\nnot an address\n1.2.3\n1.2.3.4.5\n256.1.1.1\n1.2.3.4 - nope , 0 , x\n1.1.1.1",
+        );
+        assert_eq!(filter.rejected_lines(), 5);
+        assert_eq!(filter.len(), 1);
+    }
+
+    #[test]
+    fn overlapping_ranges_merge() {
+        let filter = IpFilter::parse(
+            "1.0.0.0-1.0.0.10\n1.0.0.5-1.0.0.20\n1.0.0.21-1.0.0.30\n1.0.0.40-1.0.0.50\n1.0.0.45",
+        );
+        assert_eq!(filter.len(), 2);
+        assert!(filter.is_blocked(v4([1, 0, 0, 25])));
+        assert!(!filter.is_blocked(v4([1, 0, 0, 35])));
+        assert!(filter.is_blocked(v4([1, 0, 0, 50])));
+    }
+
+    #[test]
+    fn merge_reaches_the_end_of_the_space() {
+        let mut ranges = vec![(5u32, u32::MAX), (0, 3), (u32::MAX, u32::MAX), (4, 4)];
+        merge(&mut ranges, |x| x.checked_add(1));
+        assert_eq!(ranges, vec![(0, u32::MAX)]);
+    }
+}
@@ -1,52 +1,138 @@
-use bendy::decoding::FromBencode;
-use rand::Rng;
+use mio::Waker;
 use std::thread::JoinHandle;
 
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::{stdout, ErrorKind, Read, Result, Write};
-use std::net::TcpStream;
-use std::sync::mpsc::channel;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, Sender};
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, RwLock};
 use std::thread;
-use std::time::Duration;
-use threadpool::ThreadPool;
+use std::time::{Duration, Instant};
 
+mod error;
+pub use crate::error::Error;
+mod event;
+pub use crate::event::{Event, EventKind};
 mod tf;
-use crate::tf::*;
+pub use crate::tf::{File, FileAttributes, Info, InfoHash, MetainfoError, TorrentFile};
 mod tracker;
 use crate::tracker::*;
+pub use crate::tracker::TrackerError;
 pub mod content;
 use content::*;
+mod resume;
+pub mod storage;
+pub use crate::resume::{FileState, PartialPiece, ResumeData};
+mod ipfilter;
+pub use crate::ipfilter::{BlockedCounts, IpFilter, PeerSource};
+mod ratelimit;
+pub use crate::ratelimit::{RateLimiter, RateLimits};
+mod stats;
+pub use crate::stats::{StatsSnapshot, TransferStats};
+mod session;
+pub use crate::session::{ConnectionLimit, ConnectionSlot, QueueLimits, Session};
+mod seeding;
+mod state;
+pub use crate::seeding::{GoalAction, SeedGoal, SeedGoals};
+pub use crate::state::TorrentState;
+use crate::state::{AtomicControl, AtomicState, Control};
+use log::{error, info, warn};
+mod wire;
+pub use crate::wire::PeerError;
+mod net;
+use crate::net::{Incoming, Swarm};
 
 const BLOCK_SIZE: u32 = 16384;
-
-#[derive(Debug)]
-pub enum TorrentState {
-    Stop,
-    Start,
-    Pause,
-}
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// Protocol violations an address may commit before it is banned.
+const MAX_VIOLATIONS: u32 = 3;
+/// How long the tracker gets to hear that we are leaving.
+const STOP_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 pub struct Torrent {
     pub content: Content,
     pub torrent_file: TorrentFile,
-    pub state: Arc<Mutex<TorrentState>>,
+    state: AtomicState,
+    control: AtomicControl,
+    //why it is in the Error state
+    error: Mutex<Option<String>>,
+    /// Where fast resume data is kept between runs.
+    pub resume_path: PathBuf,
+    //addresses of peers that sent corrupt data, never connected to again
+    banned: Mutex<HashSet<IpAddr>>,
+    //protocol violations so far, by address
+    violations: Mutex<HashMap<IpAddr, u32>>,
+    //shared with the session the torrent is in
+    ip_filter: Arc<RwLock<IpFilter>>,
+    blocked: BlockedCounts,
+    /// Limits of this torrent alone, can be changed while it runs.
+    pub rate_limits: Arc<RateLimits>,
+    /// Shared by every torrent that should count toward the same limits,
+    /// replace it before the torrent runs.
+    pub global_rate_limits: Arc<RateLimits>,
+    stats: Arc<TransferStats>,
+    //connected right now, for peer_stats
+    peers: Mutex<Vec<(SocketAddr, PeerId, Arc<TransferStats>)>>,
+    /// Shared like `global_rate_limits`, unlimited by default.
+    pub connection_limit: Arc<ConnectionLimit>,
+    /// Port announced to the tracker.
+    pub listen_port: u16,
+    //accepted connections go to run through here, None while it isn't running
+    incoming: Mutex<Option<Sender<Incoming>>>,
+    //gets run's loop out of its poll, None while it isn't running
+    waker: Mutex<Option<Waker>>,
+    //None follows global_seed_goals
+    seed_goals: Mutex<Option<SeedGoals>>,
+    /// Shared like `global_rate_limits`, used unless the torrent has goals
+    /// of its own.
+    pub global_seed_goals: Arc<Mutex<SeedGoals>>,
+    //what stopped the last run, if it was a goal
+    goal_reached: Mutex<Option<(SeedGoal, GoalAction)>>,
+    //the tracker that got event=started, it has to hear when we leave
+    announced: Mutex<Option<String>>,
+}
+
+/// How a run ended, `run` returns it once everything is closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownReport {
+    pub info_hash: [u8; 20],
+    /// Always `Stopped`, a run that failed returns its error instead.
+    pub state: TorrentState,
+    /// Connections closed on the way out.
+    pub peers_disconnected: usize,
+    pub resume_data_saved: bool,
+    /// Whether the tracker heard `event=stopped` in time, also true if
+    /// nothing was announced.
+    pub tracker_notified: bool,
+    pub files_closed: bool,
+    /// From noticing the stop until the report.
+    pub took: Duration,
+}
+
+/// Transfer statistics of one connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerStats {
+    pub addr: SocketAddr,
+    pub id: [u8; 20],
+    pub stats: StatsSnapshot,
 }
 
 impl Torrent {
+    /// Fails if the torrent file can't be read or parsed, or there is no
+    /// download folder to put it in.
     pub fn new(
         torrent_file_path: String,
         download_folder: Option<String>,
         content_events: Option<ContentEvents>,
-    ) -> Torrent {
-        let tf_raw = fs::read(&torrent_file_path).unwrap();
-        let tf = TorrentFile::from_bencode(&tf_raw).unwrap();
-        println!("{}", tf);
-        println!();
+    ) -> Result<Torrent, Error> {
+        let tf_raw = fs::read(&torrent_file_path)?;
+        let tf = TorrentFile::from_bytes(&tf_raw)?;
 
-        let mut content = Content::new(&tf, download_folder);
+        let mut content = Content::new(&tf, download_folder)?;
 
         // content.events.preallocaion_end.push(Box::new(name));
         // content.events.hash_checked.push(Box::new(|x,y| { println!("{:?}/{}",x,y );}));
@@ -54,660 +140,479 @@ impl Torrent {
         if let Some(events) = content_events {
             content.events = events;
         }
-        Torrent {
+        let resume_path = dirs::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from(&content.destination_path))
+            .join("tea_torrent")
+            .join(format!("{}.resume", tf.info_hash.as_string()));
+        Ok(Torrent {
             torrent_file: tf,
             content,
-            state: Arc::new(Mutex::new(TorrentState::Start)),
-        }
+            state: AtomicState::new(TorrentState::Stopped),
+            control: AtomicControl::new(Control::Run),
+            error: Mutex::new(None),
+            resume_path,
+            banned: Mutex::new(HashSet::new()),
+            violations: Mutex::new(HashMap::new()),
+            ip_filter: Arc::new(RwLock::new(IpFilter::new())),
+            blocked: BlockedCounts::default(),
+            rate_limits: Arc::new(RateLimits::default()),
+            global_rate_limits: Arc::new(RateLimits::default()),
+            stats: Arc::new(TransferStats::new()),
+            peers: Mutex::new(vec![]),
+            connection_limit: Arc::new(ConnectionLimit::default()),
+            listen_port: 50658,
+            incoming: Mutex::new(None),
+            waker: Mutex::new(None),
+            seed_goals: Mutex::new(None),
+            global_seed_goals: Arc::new(Mutex::new(SeedGoals::default())),
+            goal_reached: Mutex::new(None),
+            announced: Mutex::new(None),
+        })
     }
 
-    pub fn change_state(&self, state: TorrentState) {
-        *self.state.lock().unwrap() = state;
+    /// What it is doing right now, doesn't lock anything.
+    pub fn state(&self) -> TorrentState {
+        self.state.get()
     }
 
-    pub fn run(&self) {
-        let content = Arc::new(&self.content);
-        content.preallocate();
-        content.check_content_hash();
-        println!("Bitfield: {:?}", content.get_bitfield());
+    /// Why the last run failed, while the state is `Error`.
+    pub fn error(&self) -> Option<String> {
+        self.error.lock().unwrap().clone()
+    }
 
-        let r = connect_to_tracker(&self.torrent_file, &self.content);
-        if r.is_none() {
-            println!("Connection failed");
-            return;
+    /// Disconnects every peer and stops announcing, verified pieces are
+    /// kept. A torrent paused before it runs comes up paused after checking.
+    /// False if it is stopping.
+    pub fn pause(&self) -> bool {
+        let paused = self.control.replace(Control::Run, Control::Pause)
+            || self.control.get() == Control::Pause;
+        self.wake();
+        paused
+    }
+
+    /// Reconnects a paused torrent, false if it wasn't paused.
+    pub fn resume(&self) -> bool {
+        let resumed = self.control.replace(Control::Pause, Control::Run);
+        self.wake();
+        resumed
+    }
+
+    /// Makes `run` wind down and return, paused or not.
+    pub fn stop(&self) {
+        self.control.set(Control::Stop);
+        self.wake();
+    }
+
+    /// Has a running torrent look at its control and incoming connections
+    /// right away.
+    fn wake(&self) {
+        if let Some(waker) = &*self.waker.lock().unwrap() {
+            if let Err(e) = waker.wake() {
+                warn!(target: "torrent", "Couldn't wake the torrent: {}", e);
+            }
         }
+    }
 
-        let respone = r.unwrap();
-        println!("Connection complete, connecting to peers");
+    /// Transitions `can_change_to` refuses are a bug and are ignored.
+    fn set_state(&self, to: TorrentState) {
+        let from = self.state.get();
+        if from == to {
+            return;
+        }
+        if !from.can_change_to(to) {
+            warn!(
+                target: "torrent",
+                info_hash:% = self.torrent_file.info_hash.as_string();
+                "Invalid state change from {:?} to {:?}", from, to
+            );
+            return;
+        }
+        info!(
+            target: "torrent",
+            info_hash:% = self.torrent_file.info_hash.as_string();
+            "State changed to {:?}", to
+        );
+        self.state.set(to);
+        self.content
+            .events
+            .state_changed
+            .iter()
+            .for_each(|e| e(from, to));
+        self.content.emit(EventKind::StateChanged { from, to });
+    }
 
-        let mut peers = connect_to_peers(
-            respone,
-            Handshake::new(self.torrent_file.info_hash.raw()),
-            self.torrent_file.info.piece_count as usize,
+    /// Reports it and ends up in `Error`, the run returns it next.
+    fn fail(&self, error: &Error) {
+        error!(
+            target: "torrent",
+            info_hash:% = self.torrent_file.info_hash.as_string();
+            "Torrent failed: {}", error
         );
-        let mut handles: Vec<thread::JoinHandle<_>> = vec![];
-
-        let (tx, rx) = channel();
-
-        //recieving messages from peers
-        for peer in &peers {
-            let state = Arc::clone(&self.state);
-            let peer = Arc::clone(peer);
-            let tx = tx.clone();
-            let join_handle = thread::Builder::new()
-                .name(peer.id_string())
-                .spawn(move || {
-                    println!("Spawned thread {}", peer.id_string());
-                    loop {
-                        if let TorrentState::Stop = *state.lock().unwrap() {
-                            println!("Breaking thread {}", peer.id_string());
-                            break;
-                        }
-                        let message = peer.get_message();
-
-                        match message {
-                            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
-                            Err(e) => {
-                                panic!("Couldn't read buffer; {:?}", e.kind(),);
-                            }
-                            Ok(message) => match message {
-                                PeerMessage::KeepAlive => (),
-                                PeerMessage::Choke => {
-                                    println!("Choked by {}", peer.id_string());
-                                    peer.status.lock().unwrap().2 = true;
-                                }
-                                PeerMessage::Unchoke => {
-                                    println!("Unchoked by {}", peer.id_string());
-                                    peer.status.lock().unwrap().2 = false;
-                                    *peer.busy.lock().unwrap() = false;
-                                }
-                                PeerMessage::Interested => {
-                                    println!("interested");
-                                    peer.status.lock().unwrap().3 = true;
-                                }
-                                PeerMessage::NotInterested => {
-                                    println!("not interested");
-                                    peer.status.lock().unwrap().3 = false;
-                                }
-                                PeerMessage::Have(index) => {
-                                    peer.add_piece_to_bitfield(index);
-                                }
-                                PeerMessage::Bitfield(field) => {
-                                    *peer.bitfield.lock().unwrap() = field;
-                                }
-                                PeerMessage::Request(_index, _begin, _length) => {
-                                    println!("request");
-                                }
-                                PeerMessage::Piece(index, begin, block) => {
-                                    tx.send((Arc::clone(&peer), (index, begin, block))).unwrap();
-                                }
-                                PeerMessage::Cancel(_index, _begin, _length) => {
-                                    println!("cancel");
-                                }
-                                PeerMessage::Port(_port) => {
-                                    println!("port {}", peer.id_string());
-                                }
-                            },
-                        }
-                    }
-                })
-                .unwrap();
-
-            handles.push(join_handle);
+        *self.error.lock().unwrap() = Some(error.to_string());
+        self.content.events.error.iter().for_each(|e| e(error));
+        if let Some(kind) = EventKind::from_error(error) {
+            self.content.emit(kind);
         }
+        self.set_state(TorrentState::Error);
+    }
 
-        let tf = Arc::new(&self.torrent_file);
-        //recieving blocks and writing them to pieces (and then to file)
-        let content_write = Arc::clone(&content); //THIS is why SELF ESCAPES in an unscoped thread!!!!
-        thread::scope(|s| {
-            println!("Opening scope");
-            s.spawn(move || {
-                println!("Spawned write thread");
-                rx.iter().for_each(|(peer, (index, begin, block))| {
-                    let piece_number = index;
-                    let offset = begin;
-
-                    //TODO need to find a way to make peer not busy before writing the piece to file.
-                    //my theory is we will be able to download and write at the same time then
-                    //and this thread will make sense.
-                    //It will probably take more RAM though
-                    let r = content_write.add_block(piece_number as usize, offset as usize, &block);
-                    match r {
-                        Some(true) => {
-                            println!(
-                                " \x1b[92mCorrect hash!\x1b[0m Piece {} from {}",
-                                piece_number,
-                                peer.id_string(),
-                            );
-                            *peer.busy.lock().unwrap() = false;
-                        }
-                        Some(false) => {
-                            println!(
-                                " \x1b[91mHash doesn't match!\x1b[0m Piece {} from{}",
-                                piece_number,
-                                peer.id_string(),
-                            );
-                            *peer.busy.lock().unwrap() = false;
-                        }
-                        None => (),
-                    }
-                });
-                println!("Write thread DONE!");
-            });
+    /// Events of this torrent from now on. A torrent in a session sends
+    /// them to the session's subscribers too.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.content.subscribe()
+    }
 
-            //sending messages to peers
-            // let mut missing_pieces = content.missing_pieces.iter();
-            // let mut piece = missing_pieces.next();
+    /// Downloading or seeding, whichever fits the content.
+    fn set_active_state(&self) {
+        match self.content.is_finished() {
+            true => self.set_state(TorrentState::Seeding),
+            false => self.set_state(TorrentState::Downloading),
+        }
+    }
 
-            println!("Starting message loop");
-            loop {
-                if let TorrentState::Stop = *self.state.lock().unwrap() {
-                    println!("Breaking message loop");
-                    drop(tx);
-                    break;
-                }
-                let piece_o = content
-                    .pieces
-                    .iter()
-                    .find(|piece| piece.lock().unwrap().status == PieceStatus::Missing);
-                let &mut piece;
-                match piece_o {
-                    Some(p) => piece = p,
-                    None => continue,
-                }
-                let p = piece.lock().unwrap().number;
-
-                let peersclone = peers.clone();
-                let peersclone = peersclone
-                    .into_iter()
-                    .filter(|peer| {
-                        peer.has_piece(p.try_into().unwrap()) && !(*peer.busy.lock().unwrap())
-                    })
-                    .collect::<Vec<Arc<Peer>>>();
-                let peer = peersclone.first();
-
-                if peer.is_none() {
-                    continue;
-                }
+    /// Flushes storage first, resume data must not claim pieces that
+    /// could still be lost.
+    /// False if it couldn't be saved.
+    pub fn save_resume_data(&self) -> bool {
+        let info_hash = &self.torrent_file.info_hash;
+        if let Err(e) = self.content.flush() {
+            warn!(
+                target: "content",
+                info_hash:% = info_hash.as_string();
+                "Couldn't flush storage, resume data not saved: {}", e
+            );
+            return false;
+        }
+        let data = self.content.resume_data(info_hash);
+        if let Err(e) = data.save(&self.resume_path) {
+            warn!(
+                target: "content",
+                info_hash:% = info_hash.as_string();
+                "Couldn't save resume data: {}", e
+            );
+            return false;
+        }
+        true
+    }
 
-                let peer = Arc::clone(peer.unwrap());
-
-                let piece_length = if p == tf.info.piece_count - 1 {
-                    //last piece
-                    tf.info.length as u32 - (tf.info.piece_length as u32 * p)
-                } else {
-                    tf.info.piece_length
-                };
-                let res = peer.request(&peer.stream, p as u32, piece_length);
-                match res {
-                    Ok(true) => piece.lock().unwrap().make_awaiting(),
-                    Ok(false) => (),
-                    Err(_e) => {
-                        println!("\x1b[91mRemoving peer {} \x1b[0m", peer.id_string());
-                        let index = peersclone.iter().position(|x| x.id == peer.id).unwrap();
-                        peers.remove(index);
-                    }
-                }
-            }
-        });
-        println!("missing_pieces DONE!");
+    /// Takes effect right away, connected peers the new filter blocks are dropped.
+    /// A torrent in a session has the session's filter, see `Session::set_ip_filter`.
+    pub fn set_ip_filter(&self, filter: IpFilter) {
+        *self.ip_filter.write().unwrap() = filter;
+    }
 
-        for handle in handles {
-            let _r = handle.join();
+    /// The goals this torrent seeds toward.
+    pub fn seed_goals(&self) -> SeedGoals {
+        match *self.seed_goals.lock().unwrap() {
+            Some(goals) => goals,
+            None => *self.global_seed_goals.lock().unwrap(),
         }
     }
-}
 
-pub fn run_torrent(torrent: Arc<Torrent>) -> JoinHandle<()> {
-    thread::spawn(move || torrent.run())
-}
+    /// `None` goes back to the global goals. Can be changed while it runs.
+    pub fn set_seed_goals(&self, goals: Option<SeedGoals>) {
+        *self.seed_goals.lock().unwrap() = goals;
+    }
 
-fn connect_to_peers(
-    respone: TrackerResponse,
-    handshake: Handshake,
-    piece_count: usize,
-) -> Vec<Arc<Peer>> {
-    let mut streams = vec![];
-    let pool = ThreadPool::new(9);
-    let (tx, rx) = channel();
-    let respone = Arc::new(respone);
-
-    enum Result {
-        Done(Peer),
-        Error,
-        InvalidHash,
+    /// The goal that stopped the last run and what was done about it.
+    pub fn goal_reached(&self) -> Option<(SeedGoal, GoalAction)> {
+        *self.goal_reached.lock().unwrap()
     }
 
-    for i in 0..respone.peers.len() {
-        let respone = Arc::clone(&respone);
-        let tx = tx.clone();
-
-        pool.execute(move || {
-            stdout().flush().unwrap();
-            let stream = TcpStream::connect_timeout(&respone.peers[i], Duration::from_secs(2));
-
-            if let Ok(mut s) = stream {
-                //s.set_read_timeout(Some(Duration::from_secs(15))).unwrap();
-                //writing handshake
-                let _r = s
-                    .write(&handshake.raw)
-                    .expect("Couldn't write buffer; Handshake");
-                //reading handshake
-                let mut peer_handshake = [0u8; 68];
-                let _r = s.read_exact(&mut peer_handshake);
-
-                if let Err(_e) = _r {
-                    tx.send((Result::Error, respone.peers[i]))
-                        .expect("channel will be there waiting for the pool");
-                    return;
-                }
-                if peer_handshake[28..48] != handshake.raw[28..48] {
-                    tx.send((Result::InvalidHash, respone.peers[i]))
-                        .expect("channel will be there waiting for the pool");
-                    return;
-                }
-                let mut peer_id = [0; 20];
-                peer_id.clone_from_slice(&peer_handshake[48..68]);
-                // s.set_nonblocking(true);
-                tx.send((
-                    Result::Done(Peer {
-                        id: peer_id,
-                        stream: s,
-                        bitfield: Mutex::new(vec![0; piece_count]),
-                        status: Mutex::new((true, false, true, false)),
-                        busy: Mutex::new(false),
-                    }),
-                    respone.peers[i],
-                ))
-                .expect("channel will be there waiting for the pool");
-            } else {
-                tx.send((Result::Error, respone.peers[i]))
-                    .expect("channel will be there waiting for the pool");
+    /// Deletes the downloaded files and the resume data, only while the
+    /// torrent isn't running.
+    fn delete_data(&self) {
+        let info_hash = self.torrent_file.info_hash.as_string();
+        if let Err(e) = self.content.delete_files() {
+            warn!(target: "content", info_hash; "Couldn't delete files: {}", e);
+        }
+        match fs::remove_file(&self.resume_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                warn!(target: "content", info_hash; "Couldn't delete resume data: {}", e)
             }
-        });
+            _ => (),
+        }
     }
 
-    rx.iter().take(respone.peers.len()).for_each(|(res, peer)| {
-        print!("{} ", peer);
-        //TODO maybe need a result instead of just enum?
-        match res {
-            Result::Done(s) => {
-                println!("\x1b[1mDone!\x1b[0m {}", s.try_parse_client());
-                streams.push(Arc::new(s));
-            }
-            Result::Error => {
-                println!("\x1b[91mFailed!\x1b[0m");
-            }
-            Result::InvalidHash => {
-                println!("\x1b[91mInvalid info hash!\x1b[0m");
-            }
+    pub fn blocked_connections(&self) -> &BlockedCounts {
+        &self.blocked
+    }
+
+    /// Whether a peer may be connected to or accepted, asked for every
+    /// address whatever its source. Refusals are counted.
+    pub fn allows_peer(&self, ip: IpAddr, source: PeerSource) -> bool {
+        if self.banned.lock().unwrap().contains(&ip) {
+            return false;
         }
-    });
-    streams
-}
+        if self.ip_filter.read().unwrap().is_blocked(ip) {
+            self.blocked.add(source);
+            return false;
+        }
+        true
+    }
 
-#[derive(Debug)]
-struct Handshake {
-    raw: [u8; 68],
-}
+    /// Totals of every connection since the torrent was created.
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
 
-impl Handshake {
-    fn new(info_hash: &[u8; 20]) -> Handshake {
-        let version = str::replace(env!("CARGO_PKG_VERSION"), ".", "");
-        let mut arr = vec![19];
-        arr.extend(b"BitTorrent protocol");
-        arr.extend([0, 0, 0, 0, 0, 0, 0, 0]);
-        arr.extend(info_hash);
-        arr.extend(b"-tT");
-        arr.extend(version.as_bytes());
-        arr.extend(b"R-");
-
-        let random_id: [u8; 12] = (0..12)
-            .map(|_| rand::thread_rng().gen_range(48..58))
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap();
-        arr.extend(&random_id);
-        let raw = arr.try_into().unwrap();
-        Handshake { raw }
+    pub fn peer_stats(&self) -> Vec<PeerStats> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, id, stats)| PeerStats {
+                addr: *addr,
+                id: *id,
+                stats: stats.snapshot(),
+            })
+            .collect()
     }
-}
 
-#[derive(Debug)]
-pub struct Peer {
-    id: [u8; 20],
-    stream: TcpStream,
-    bitfield: Mutex<Vec<u8>>,
-    //am_choking = 1, am_interested = 0, peer_choking = 1, peer_interested = 0
-    status: Mutex<(bool, bool, bool, bool)>,
-    busy: Mutex<bool>,
-}
+    /// Addresses banned in this session for sending corrupt data.
+    pub fn banned_ips(&self) -> Vec<IpAddr> {
+        self.banned.lock().unwrap().iter().copied().collect()
+    }
 
-impl Peer {
-    fn get_message(&self) -> Result<PeerMessage> {
-        let mut message_size = [0u8; 4];
-        let mut stream = &self.stream;
-        let package_size = stream.read_exact(&mut message_size);
-        match package_size {
-            Ok(_package_size) => {}
-            Err(e) => {
-                println!("No package for some secs; read timeout; {}", e);
-
-                println!("Send keep-alive");
-                let r = stream.write(&[0, 0, 0, 0]);
-                match r {
-                    Err(e) if e.kind() == ErrorKind::Interrupted => return Err(e),
-                    Err(e) if e.kind() == ErrorKind::ConnectionReset => {
-                        println!("\x1b[91mConnection Reset\x1b[0m {}", self.id_string());
-                        panic!("{:?}", e.kind());
-                    }
-                    Err(e) if e.kind() == ErrorKind::ConnectionAborted => {
-                        println!("\x1b[91mConnection aborted\x1b[0m {}", self.id_string());
-                        panic!("{:?}", e.kind());
-                    }
-                    Err(e) => println!("Error writing buffer: {:?}", e),
-                    _ => (),
-                }
+    /// Bans the addresses found to have sent corrupt data for a piece,
+    /// connected or not. The run drops every connection from them on its
+    /// next turn.
+    fn ban_corrupt_peers(&self, piece: u32) {
+        for ip in self.content.take_corrupt_peers(piece as usize) {
+            if self.banned.lock().unwrap().insert(ip) {
+                warn!(
+                    target: "peer",
+                    info_hash:% = self.torrent_file.info_hash.as_string(),
+                    ip:%;
+                    "Banning, corrupt data in piece {}", piece
+                );
+                self.content.emit(EventKind::PeerBanned { ip });
+                self.content
+                    .events
+                    .peer_banned
+                    .iter()
+                    .for_each(|e| e(ip, piece));
             }
         }
-        let message_size = u32::from_be_bytes(message_size);
-        if message_size == 0 {
-            return Ok(PeerMessage::KeepAlive);
+    }
+
+    /// Counts a protocol violation against the address, banning it once
+    /// there are too many.
+    fn add_violation(&self, ip: IpAddr) {
+        let mut violations = self.violations.lock().unwrap();
+        let count = violations.entry(ip).or_insert(0);
+        *count += 1;
+        if *count >= MAX_VIOLATIONS && self.banned.lock().unwrap().insert(ip) {
+            warn!(
+                target: "peer",
+                info_hash:% = self.torrent_file.info_hash.as_string(),
+                ip:%;
+                "Banning, {} protocol violations", count
+            );
+            self.content.emit(EventKind::PeerBanned { ip });
         }
+    }
 
-        let mut message_buf = vec![0u8; message_size as usize];
-        stream.read_exact(&mut message_buf)?;
-        /*
-            keep-alive: <len=0000>
-            choke: <len=0001><id=0>
-            unchoke: <len=0001><id=1>
-            interested: <len=0001><id=2>
-            not interested: <len=0001><id=3>
-            have: <len=0005><id=4><piece index>
-            bitfield: <len=0001+X><id=5><bitfield>
-            request: <len=0013><id=6><index><begin><length>
-            piece: <len=0009+X><id=7><index><begin><block>
-            cancel: <len=0013><id=8><index><begin><length>
-            port: <len=0003><id=9><listen-port>
-        */
-        let message = match &message_buf[0] {
-            0 => PeerMessage::Choke,
-            1 => PeerMessage::Unchoke,
-            2 => PeerMessage::Interested,
-            3 => PeerMessage::NotInterested,
-            4 => PeerMessage::Have(u32::from_be_bytes(message_buf[1..5].try_into().unwrap())),
-            5 => PeerMessage::Bitfield(message_buf[1..].to_vec()),
-            6 => PeerMessage::Request(
-                u32::from_be_bytes(message_buf[1..5].try_into().unwrap()),
-                u32::from_be_bytes(message_buf[5..9].try_into().unwrap()),
-                u32::from_be_bytes(message_buf[9..].try_into().unwrap()),
-            ),
-            7 => PeerMessage::Piece(
-                u32::from_be_bytes(message_buf[1..5].try_into().unwrap()),
-                u32::from_be_bytes(message_buf[5..9].try_into().unwrap()),
-                message_buf[9..].to_vec(),
-            ),
-            8 => PeerMessage::Cancel(
-                u32::from_be_bytes(message_buf[1..5].try_into().unwrap()),
-                u32::from_be_bytes(message_buf[5..9].try_into().unwrap()),
-                u32::from_be_bytes(message_buf[9..13].try_into().unwrap()),
-            ),
-            9 => PeerMessage::Port(u32::from_be_bytes(message_buf[1..5].try_into().unwrap())),
-            _ => {
-                panic!("Unknown message!");
-            }
-        };
-        Ok(message)
+    /// Announces and returns the peers the tracker knows that may be
+    /// connected to, fails if no tracker answered.
+    fn find_peers(&self) -> Result<Vec<SocketAddr>, TrackerError> {
+        let (tracker, mut respone) = connect_to_tracker(
+            &self.torrent_file,
+            &self.content,
+            &self.stats(),
+            self.listen_port,
+            &|e| self.content.report_error(e.into()),
+        )?;
+        info!(
+            target: "tracker",
+            info_hash:% = self.torrent_file.info_hash.as_string(),
+            url:% = tracker;
+            "{} peers, next announce in {}s", respone.peers.len(), respone.interval
+        );
+        self.content.emit(EventKind::TrackerReply {
+            url: tracker.clone(),
+            peers: respone.peers.len(),
+            interval: Duration::from_secs(respone.interval as u64),
+        });
+        *self.announced.lock().unwrap() = Some(tracker);
+        respone
+            .peers
+            .retain(|addr| self.allows_peer(addr.ip(), PeerSource::Tracker));
+        Ok(respone.peers)
     }
 
-    fn request(
-        &self,
-        mut stream: &TcpStream,
-        piece_number: u32,
-        piece_length: u32,
-    ) -> Result<bool> {
-        if let Ok(mut st) = self.status.lock() {
-            if !st.1 && st.2 {
-                println!("Sending unchoke and interested");
-                //send unchoke and interested
-                let r = stream.write(&[0, 0, 0, 1, 1, 0, 0, 0, 1, 2]);
-                match r {
-                    Err(e) if e.kind() == ErrorKind::Interrupted => {
-                        println!("\x1b[91mInterrupted\x1b[0m {}", self.id_string());
-                        return Ok(false);
-                    }
-                    Err(e) => println!("Error writing buffer: {:?}", e),
-                    _ => {}
-                }
-                st.0 = false;
-                st.1 = true;
-                *self.busy.lock().unwrap() = true;
-                return Ok(false);
-            }
+    /// Takes a connection the session accepted for this torrent. The peer
+    /// has sent its handshake already, ours goes back once the run picks
+    /// the connection up.
+    pub(crate) fn accept_peer(&self, stream: TcpStream, addr: SocketAddr, handshake: &[u8; 68]) {
+        if !self.allows_peer(addr.ip(), PeerSource::Incoming) {
+            return;
         }
-
-        if self.status.lock().unwrap().2 {
-            // println!("Still choked");
-            return Ok(false);
+        let Some(incoming) = self.incoming.lock().unwrap().clone() else {
+            return;
+        };
+        let Some(slot) = self.connection_limit.acquire() else {
+            return;
+        };
+        let mut id = [0; 20];
+        id.copy_from_slice(&handshake[48..68]);
+        let accepted = Incoming {
+            stream,
+            addr,
+            id,
+            slot,
+        };
+        if incoming.send(accepted).is_ok() {
+            self.wake();
         }
+    }
 
-        println!("Request {} from {}", piece_number, self.id_string());
-        let mut offset: u32 = 0;
-        let mut left = piece_length;
-        *self.busy.lock().unwrap() = true;
-
-        while left > 0 {
-            let block_size = if left < BLOCK_SIZE {
-                //bitwise magic! this finds the rightmost bit it last_piece_size
-                // piece_length & (!(piece_length - 1))
-                //and this finds the rightmost bit it last_piece_size
-                1 << (31 - left.leading_zeros())
-            } else {
-                BLOCK_SIZE
-            };
-
-            let mut request_message = vec![0, 0, 0, 13, 6]; //constant part
-            request_message.append(&mut piece_number.to_be_bytes().to_vec());
-            let be_offset = offset.to_be_bytes();
-            request_message.append(&mut be_offset.to_vec()); //piece uhh, offset?
-            request_message.append(&mut block_size.to_be_bytes().to_vec());
-            // println!("request_message {:?}", request_message);
-            // println!("Request message {:?} from {}", &request_message, self.id_string());
-            match stream.write(&request_message) {
-                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
-                Err(e) => {
-                    println!("\x1b[91mError writing buffer: {:?}\x1b[0m", e);
-                }
-                _ => {}
+    /// Tells the tracker that was announced to that we are gone, true if
+    /// there was none.
+    fn announce_stopped(&self) -> bool {
+        let Some(tracker) = self.announced.lock().unwrap().take() else {
+            return true;
+        };
+        let announced = tracker::announce_stopped(
+            &tracker,
+            &self.torrent_file,
+            &self.content,
+            &self.stats(),
+            self.listen_port,
+            STOP_ANNOUNCE_TIMEOUT,
+        );
+        match announced {
+            Ok(()) => true,
+            Err(e) => {
+                self.content.report_error(e.into());
+                false
             }
+        }
+    }
 
-            left -= block_size;
-            offset += block_size;
+    fn close_files(&self) -> bool {
+        if let Err(e) = self.content.close() {
+            warn!(
+                target: "content",
+                info_hash:% = self.torrent_file.info_hash.as_string();
+                "Couldn't close files: {}", e
+            );
+            return false;
         }
-        Ok(true)
+        true
     }
 
-    fn try_parse_client(&self) -> String {
-        let huh = [self.id[1], self.id[2]];
-
-        match &huh {
-            b"7T" => String::from("aTorrent for Android"),
-            b"AB" => String::from("AnyEvent::BitTorrent"),
-            b"AG" => String::from("Ares"),
-            b"A~" => String::from("Ares"),
-            b"AR" => String::from("Arctic"),
-            b"AV" => String::from("Avicora"),
-            b"AT" => String::from("Artemis"),
-            b"AX" => String::from("BitPump"),
-            b"AZ" => String::from("Azureus"),
-            b"BB" => String::from("BitBuddy"),
-            b"BC" => String::from("BitComet"),
-            b"BE" => String::from("Baretorrent"),
-            b"BF" => String::from("Bitflu"),
-            b"BG" => String::from("BTG (uses Rasterbar libtorrent)"),
-            b"BL" => String::from("BitCometLite (uses 6 digit version number) or BitBlinder"),
-            b"BP" => String::from("BitTorrent Pro (Azureus + spyware)"),
-            b"BR" => String::from("BitRocket"),
-            b"BS" => String::from("BTSlave"),
-            b"BT" => String::from("mainline BitTorrent (versions >= 7.9) or BBtor"),
-            b"Bt" => String::from("Bt"),
-            b"BW" => String::from("BitWombat"),
-            b"BX" => String::from("~Bittorrent X"),
-            b"CD" => String::from("Enhanced CTorrent"),
-            b"CT" => String::from("CTorrent"),
-            b"DE" => String::from("DelugeTorrent"),
-            b"DP" => String::from("Propagate Data Client"),
-            b"EB" => String::from("EBit"),
-            b"ES" => String::from("electric sheep"),
-            b"FC" => String::from("FileCroc"),
-            b"FD" => String::from("Free Download Manager (versions >= 5.1.12)"),
-            b"FT" => String::from("FoxTorrent"),
-            b"FX" => String::from("Freebox BitTorrent"),
-            b"GS" => String::from("GSTorrent"),
-            b"HK" => String::from("Hekate"),
-            b"HL" => String::from("Halite"),
-            b"HM" => String::from("hMule (uses Rasterbar libtorrent)"),
-            b"HN" => String::from("Hydranode"),
-            b"IL" => String::from("iLivid"),
-            b"JS" => String::from("Justseed.it client"),
-            b"JT" => String::from("JavaTorrent"),
-            b"KG" => String::from("KGet"),
-            b"KT" => String::from("KTorrent"),
-            b"LC" => String::from("LeechCraft"),
-            b"LH" => String::from("LH-ABC"),
-            b"LP" => String::from("Lphant"),
-            b"LT" => String::from("libtorrent"),
-            b"lt" => String::from("libTorrent"),
-            b"LW" => String::from("LimeWire"),
-            b"MK" => String::from("Meerkat"),
-            b"MO" => String::from("MonoTorrent"),
-            b"MP" => String::from("MooPolice"),
-            b"MR" => String::from("Miro"),
-            b"MT" => String::from("MoonlightTorrent"),
-            b"NB" => String::from("Net::BitTorrent"),
-            b"NX" => String::from("Net Transport"),
-            b"OS" => String::from("OneSwarm"),
-            b"OT" => String::from("OmegaTorrent"),
-            b"PB" => String::from("Protocol::BitTorrent"),
-            b"PD" => String::from("Pando"),
-            b"PI" => String::from("PicoTorrent"),
-            b"PT" => String::from("PHPTracker"),
-            b"qB" => String::from("qBittorrent"),
-            b"QD" => String::from("QQDownload"),
-            b"QT" => String::from("Qt 4 Torrent example"),
-            b"RT" => String::from("Retriever"),
-            b"RZ" => String::from("RezTorrent"),
-            b"S~" => String::from("Shareaza alpha/beta"),
-            b"SB" => String::from("~Swiftbit"),
-            b"SD" => String::from("Thunder (aka XùnLéi)"),
-            b"SM" => String::from("SoMud"),
-            b"SP" => String::from("BitSpirit"),
-            b"SS" => String::from("SwarmScope"),
-            b"ST" => String::from("SymTorrent"),
-            b"st" => String::from("sharktorrent"),
-            b"SZ" => String::from("Shareaza"),
-            b"TB" => String::from("Torch"),
-            b"TE" => String::from("terasaur Seed Bank"),
-            b"TL" => String::from("Tribler (versions >= 6.1.0)"),
-            b"TN" => String::from("TorrentDotNET"),
-            b"TR" => String::from("Transmission"),
-            b"TS" => String::from("Torrentstorm"),
-            b"TT" => String::from("TuoTu"),
-            b"UL" => String::from("uLeecher!"),
-            b"UM" => String::from("µTorrent for Mac"),
-            b"UT" => String::from("µTorrent"),
-            b"VG" => String::from("Vagaa"),
-            b"WD" => String::from("WebTorrent Desktop"),
-            b"WT" => String::from("BitLet"),
-            b"WW" => String::from("WebTorrent"),
-            b"WY" => String::from("FireTorrent"),
-            b"XF" => String::from("Xfplay"),
-            b"XL" => String::from("Xunlei"),
-            b"XS" => String::from("XSwifter"),
-            b"XT" => String::from("XanTorrent"),
-            b"XX" => String::from("Xtorrent"),
-            b"ZT" => String::from("ZipTorrent"),
-            b"ZO" => String::from("ZONA ?"),
-            _ => String::from("unknown client"),
+    fn report(&self, since: Instant) -> ShutdownReport {
+        ShutdownReport {
+            info_hash: *self.torrent_file.info_hash.raw(),
+            state: self.state(),
+            peers_disconnected: 0,
+            resume_data_saved: false,
+            tracker_notified: true,
+            files_closed: false,
+            took: since.elapsed(),
         }
     }
 
-    fn id_string(&self) -> String {
-        format!(
-            "{}:{}.{}.{}.{}.{}.{}.{}.{}.{}.{}.{}.{}",
-            String::from_utf8_lossy(&self.id[..8]),
-            &self.id[8],
-            &self.id[9],
-            &self.id[10],
-            &self.id[11],
-            &self.id[12],
-            &self.id[13],
-            &self.id[14],
-            &self.id[15],
-            &self.id[16],
-            &self.id[17],
-            &self.id[18],
-            &self.id[19],
-        )
+    /// Runs until stopped. A `stop` asked for before makes it return right
+    /// away, a pause is kept. It can be run again once it returned.
+    ///
+    /// Stopping closes every connection, writes the blocks already received,
+    /// saves resume data and tells the tracker, in that order.
+    ///
+    /// Fails if the files can't be allocated or no tracker answers, the
+    /// state is `Error` then. Failures later on go to the `error` event.
+    pub fn run(&self) -> Result<ShutdownReport, Error> {
+        let result = self.run_until_stopped();
+        //the stop is used up, the next run goes on until it is stopped again
+        self.control.replace(Control::Stop, Control::Run);
+        result
     }
 
-    fn has_piece(&self, piece_number: usize) -> bool {
-        let byte = piece_number / 8;
-        let bit = (piece_number % 8) as u8;
-        let field = (*self.bitfield.lock().unwrap())[byte as usize];
-        match bit {
-            0 => field & 0b10000000 == 0b10000000,
-            1 => field & 0b01000000 == 0b01000000,
-            2 => field & 0b00100000 == 0b00100000,
-            3 => field & 0b00010000 == 0b00010000,
-            4 => field & 0b00001000 == 0b00001000,
-            5 => field & 0b00000100 == 0b00000100,
-            6 => field & 0b00000010 == 0b00000010,
-            7 => field & 0b00000001 == 0b00000001,
-            _ => unreachable!(),
+    fn run_until_stopped(&self) -> Result<ShutdownReport, Error> {
+        *self.goal_reached.lock().unwrap() = None;
+        *self.error.lock().unwrap() = None;
+        self.set_state(TorrentState::Checking);
+        let content = &self.content;
+        let resume = ResumeData::load(&self.resume_path).filter(|r| {
+            &r.info_hash == self.torrent_file.info_hash.raw()
+                && r.destination == content.destination_path
+                && r.files.len() == content.files().len()
+        });
+        //priorities first, skipped files are not allocated
+        if let Some(resume) = &resume {
+            content.set_file_priorities(&resume.priorities);
+        }
+        if let Err(e) = content.preallocate() {
+            self.fail(&e);
+            return Err(e);
+        }
+        let should_stop = || self.control.get() == Control::Stop;
+        let checked = match &resume {
+            Some(resume) => content.resume(resume, &should_stop),
+            None => content.check_content_hash(&should_stop),
+        };
+        //unchecked pieces look missing, saving now would throw the old resume data away
+        if !checked {
+            info!(
+                target: "content",
+                info_hash:% = self.torrent_file.info_hash.as_string();
+                "Hash check stopped"
+            );
+            let since = Instant::now();
+            let files_closed = self.close_files();
+            self.set_state(TorrentState::Stopped);
+            return Ok(ShutdownReport {
+                files_closed,
+                ..self.report(since)
+            });
+        }
+        if content.is_finished() {
+            content.finalize_files();
         }
-    }
 
-    fn add_piece_to_bitfield(&self, piece_number: u32) {
-        let byte = piece_number / 8;
-        let bit = (piece_number % 8) as u8;
-        self.bitfield.lock().unwrap()[byte as usize] |= match bit {
-            0 => 0b10000000,
-            1 => 0b01000000,
-            2 => 0b00100000,
-            3 => 0b00010000,
-            4 => 0b00001000,
-            5 => 0b00000100,
-            6 => 0b00000010,
-            7 => 0b00000001,
-            _ => unreachable!(),
+        let mut swarm = match Swarm::new(self) {
+            Ok(swarm) => swarm,
+            Err(e) => {
+                let e = Error::from(e);
+                self.fail(&e);
+                self.close_files();
+                return Err(e);
+            }
         };
+        //paused ones connect once they are resumed
+        if self.control.get() != Control::Pause {
+            match self.find_peers() {
+                Ok(found) => swarm.connect(found),
+                Err(e) => {
+                    let e = Error::from(e);
+                    self.fail(&e);
+                    self.save_resume_data();
+                    self.close_files();
+                    return Err(e);
+                }
+            }
+            self.set_active_state();
+        }
+        let (since, peers_disconnected) = swarm.run();
+        drop(swarm);
+        let resume_data_saved = self.save_resume_data();
+        let tracker_notified = self.announce_stopped();
+        let files_closed = self.close_files();
+        if let Some((_, GoalAction::RemoveWithData)) = self.goal_reached() {
+            self.delete_data();
+        }
+        self.set_state(TorrentState::Stopped);
+        Ok(ShutdownReport {
+            peers_disconnected,
+            resume_data_saved,
+            tracker_notified,
+            files_closed,
+            ..self.report(since)
+        })
     }
 }
-/*
-    keep-alive: <len=0000>
-    choke: <len=0001><id=0>
-    unchoke: <len=0001><id=1>
-    interested: <len=0001><id=2>
-    not interested: <len=0001><id=3>
-    have: <len=0005><id=4><piece index>
-    bitfield: <len=0001+X><id=5><bitfield>
-    request: <len=0013><id=6><index><begin><length>
-    piece: <len=0009+X><id=7><index><begin><block>
-    cancel: <len=0013><id=8><index><begin><length>
-    port: <len=0003><id=9><listen-port>
-*/
-#[derive(Debug)]
-enum PeerMessage {
-    KeepAlive,
-    Choke,
-    Unchoke,
-    Interested,
-    NotInterested,
-    Have(u32),
-    Bitfield(Vec<u8>),
-    Request(u32, u32, u32),
-    Piece(u32, u32, Vec<u8>),
-    Cancel(u32, u32, u32),
-    Port(u32),
+
+pub fn run_torrent(torrent: Arc<Torrent>) -> JoinHandle<Result<ShutdownReport, Error>> {
+    thread::spawn(move || torrent.run())
 }
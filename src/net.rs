@@ -0,0 +1,562 @@
+//! The peers of a running torrent, all served from the thread `run` is on.
+//!
+//! Sockets are non-blocking and registered with a `mio::Poll`. The loop
+//! sleeps until a socket is ready, a timer is due or the torrent is told to
+//! pause, resume or stop, which wake it through a `Waker`. Blocks are hashed
+//! and written between turns, so a slow disk slows every peer down equally.
+use crate::content::PeerId;
+use crate::seeding::Seeding;
+use crate::state::Control;
+use crate::wire::PeerMessage;
+use crate::{ConnectionSlot, EventKind, Torrent, TorrentState, BLOCK_SIZE, RESUME_SAVE_INTERVAL};
+use log::{debug, info, log, trace, warn, Level};
+use mio::{Events, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
+mod peer;
+use crate::net::peer::Peer;
+
+const WAKER: Token = Token(0);
+/// Longest the loop sleeps, the torrent's own timers run this often.
+const TICK: Duration = Duration::from_secs(1);
+/// Requests a peer may have waiting, more are ignored until some are served.
+const MAX_THEIR_REQUESTS: usize = 256;
+
+/// A connection the session accepted, on its way to the torrent's loop.
+pub(crate) struct Incoming {
+    pub(crate) stream: TcpStream,
+    pub(crate) addr: SocketAddr,
+    pub(crate) id: PeerId,
+    pub(crate) slot: ConnectionSlot,
+}
+
+pub(crate) struct Swarm<'a> {
+    torrent: &'a Torrent,
+    poll: Poll,
+    events: Events,
+    peers: HashMap<Token, Peer>,
+    next_token: usize,
+    incoming: Receiver<Incoming>,
+    //handed to the torrent again after a pause
+    incoming_tx: Sender<Incoming>,
+    //open peers came or went, torrent.peers is out of date
+    changed: bool,
+}
+
+impl<'a> Swarm<'a> {
+    /// Takes the torrent's incoming connections and wakeups until dropped.
+    pub(crate) fn new(torrent: &'a Torrent) -> io::Result<Swarm<'a>> {
+        let poll = Poll::new()?;
+        let waker = Waker::new(poll.registry(), WAKER)?;
+        let (incoming_tx, incoming) = channel();
+        *torrent.waker.lock().unwrap() = Some(waker);
+        *torrent.incoming.lock().unwrap() = Some(incoming_tx.clone());
+        Ok(Swarm {
+            torrent,
+            poll,
+            events: Events::with_capacity(256),
+            peers: HashMap::new(),
+            next_token: WAKER.0 + 1,
+            incoming,
+            incoming_tx,
+            changed: false,
+        })
+    }
+
+    /// Starts connecting to each address, as many as the connection limit allows.
+    pub(crate) fn connect(&mut self, addrs: Vec<SocketAddr>) {
+        for addr in addrs {
+            let Some(slot) = self.torrent.connection_limit.acquire() else {
+                debug!(target: "peer", "Connection limit reached");
+                break;
+            };
+            match Peer::connect(self.torrent, addr, slot) {
+                Ok(peer) => self.add(peer),
+                Err(e) => debug!(target: "peer", addr:%; "Connection failed: {}", e),
+            }
+        }
+    }
+
+    fn add(&mut self, mut peer: Peer) {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        if let Err(e) = peer.register(self.poll.registry(), token) {
+            debug!(target: "peer", addr:% = peer.addr; "Couldn't register connection: {}", e);
+            return;
+        }
+        self.changed |= peer.is_open();
+        self.peers.insert(token, peer);
+    }
+
+    /// Runs until the torrent is stopped, returns when that was noticed and
+    /// how many connections were closed then.
+    pub(crate) fn run(&mut self) -> (Instant, usize) {
+        let torrent = self.torrent;
+        let content = &torrent.content;
+        let mut last_save = Instant::now();
+        let mut last_tick = Instant::now();
+        let mut seeding: Option<Seeding> = None;
+        loop {
+            match torrent.control.get() {
+                Control::Stop => {
+                    let since = Instant::now();
+                    *torrent.incoming.lock().unwrap() = None;
+                    let closed = self.peers.len();
+                    self.close_all();
+                    return (since, closed);
+                }
+                Control::Pause => {
+                    if torrent.state() != TorrentState::Paused {
+                        *torrent.incoming.lock().unwrap() = None;
+                        self.close_all();
+                        torrent.save_resume_data();
+                        torrent.announce_stopped();
+                        seeding = None;
+                        torrent.set_state(TorrentState::Paused);
+                    }
+                    //nothing to do until resume or stop wakes us
+                    self.poll(None);
+                    continue;
+                }
+                Control::Run if torrent.state() == TorrentState::Paused => {
+                    *torrent.incoming.lock().unwrap() = Some(self.incoming_tx.clone());
+                    match torrent.find_peers() {
+                        Ok(found) => self.connect(found),
+                        //incoming connections may still come
+                        Err(e) => content.report_error(e.into()),
+                    }
+                    torrent.set_active_state();
+                }
+                Control::Run => (),
+            }
+            self.accept_incoming();
+            if last_tick.elapsed() >= TICK {
+                last_tick = Instant::now();
+                if last_save.elapsed() > RESUME_SAVE_INTERVAL {
+                    torrent.save_resume_data();
+                    last_save = Instant::now();
+                }
+                if content.is_finished() {
+                    torrent.set_state(TorrentState::Seeding);
+                    let stats = torrent.stats();
+                    let goals = torrent.seed_goals();
+                    let reached = seeding.get_or_insert_with(|| Seeding::new(&stats)).check(
+                        &goals,
+                        &stats,
+                        torrent.torrent_file.info.length as u64,
+                    );
+                    if let Some(goal) = reached {
+                        info!(
+                            target: "torrent",
+                            info_hash:% = torrent.torrent_file.info_hash.as_string();
+                            "Seed goal reached: {:?}", goal
+                        );
+                        *torrent.goal_reached.lock().unwrap() = Some((goal, goals.action));
+                        content
+                            .events
+                            .seed_goal_reached
+                            .iter()
+                            .for_each(|e| e(goal, goals.action));
+                        //stops like any other stop, on the next turn
+                        torrent.stop();
+                        continue;
+                    }
+                } else {
+                    //a file that stopped being skipped, downloading again
+                    torrent.set_state(TorrentState::Downloading);
+                    seeding = None;
+                }
+            }
+            let now = Instant::now();
+            let mut finished = vec![];
+            for peer in self.peers.values_mut() {
+                self.changed |= serve(torrent, peer, now, &mut finished);
+            }
+            self.announce_pieces(&finished);
+            //requests queued now make the poll below return right away
+            self.request_pieces();
+            self.drop_blocked();
+            self.remove_closed();
+            self.publish();
+            let timeout = self
+                .peers
+                .values()
+                .filter_map(Peer::wake_at)
+                .min()
+                .map_or(TICK, |at| at.saturating_duration_since(now).min(TICK));
+            self.poll(Some(timeout));
+        }
+    }
+
+    /// Sleeps until something is ready, `None` waits for the waker.
+    fn poll(&mut self, timeout: Option<Duration>) {
+        if let Err(e) = self.poll.poll(&mut self.events, timeout) {
+            //interrupted by a signal, the next turn polls again
+            if e.kind() != io::ErrorKind::Interrupted {
+                warn!(target: "peer", "Polling failed: {}", e);
+            }
+            return;
+        }
+        for event in &self.events {
+            if let Some(peer) = self.peers.get_mut(&event.token()) {
+                peer.ready(event);
+            }
+        }
+    }
+
+    /// Connections the session accepted for us.
+    fn accept_incoming(&mut self) {
+        let torrent = self.torrent;
+        while let Ok(Incoming {
+            stream,
+            addr,
+            id,
+            slot,
+        }) = self.incoming.try_recv()
+        {
+            match Peer::accept(torrent, stream, addr, id, slot) {
+                Ok(mut peer) => {
+                    send_bitfield(torrent, &mut peer);
+                    debug!(
+                        target: "peer",
+                        info_hash:% = torrent.torrent_file.info_hash.as_string(),
+                        addr:%,
+                        peer:% = peer.id_string();
+                        "Accepted"
+                    );
+                    torrent.content.emit(EventKind::PeerConnected {
+                        addr,
+                        id,
+                        client: peer.try_parse_client(),
+                    });
+                    self.add(peer);
+                }
+                Err(e) => debug!(target: "peer", addr:%; "Couldn't accept: {}", e),
+            }
+        }
+    }
+
+    /// Asks idle peers for the most wanted pieces they have, and tells the
+    /// ones choking us that we are interested.
+    fn request_pieces(&mut self) {
+        if !self.peers.values().any(Peer::is_idle) {
+            return;
+        }
+        let content = &self.torrent.content;
+        for &piece in content.wanted_pieces().iter() {
+            for peer in self
+                .peers
+                .values_mut()
+                .filter(|peer| peer.is_idle() && peer.has_piece(piece as usize))
+            {
+                if peer.peer_choking {
+                    debug!(target: "peer", addr:% = peer.addr; "Sending unchoke and interested");
+                    unchoke(peer);
+                    peer.send(PeerMessage::Interested);
+                    peer.am_interested = true;
+                    continue;
+                }
+                //marked before asking, blocks may arrive right away
+                let Some(blocks) = content.request_piece(piece as usize, &peer.id) else {
+                    break;
+                };
+                debug!(target: "peer", addr:% = peer.addr; "Requesting piece {}", piece);
+                for (offset, length) in blocks {
+                    peer.send(PeerMessage::Request(piece, offset, length));
+                }
+                peer.requested = Some((piece, Instant::now()));
+                break;
+            }
+        }
+    }
+
+    /// Tells every peer that doesn't have them about pieces we just got.
+    fn announce_pieces(&mut self, pieces: &[u32]) {
+        if pieces.is_empty() {
+            return;
+        }
+        for peer in self.peers.values_mut().filter(|p| p.is_open() && !p.closed) {
+            for &piece in pieces {
+                if !peer.has_piece(piece as usize) {
+                    peer.send(PeerMessage::Have(piece));
+                }
+            }
+        }
+    }
+
+    /// Closes connections from addresses banned or filtered since they
+    /// connected.
+    fn drop_blocked(&mut self) {
+        let filter = self.torrent.ip_filter.read().unwrap();
+        let banned = self.torrent.banned.lock().unwrap();
+        for peer in self.peers.values_mut().filter(|peer| !peer.closed) {
+            let ip = peer.addr.ip();
+            if banned.contains(&ip) || filter.is_blocked(ip) {
+                peer.close(None);
+            }
+        }
+    }
+
+    fn close_all(&mut self) {
+        for peer in self.peers.values_mut() {
+            peer.close(None);
+        }
+        self.remove_closed();
+        self.publish();
+    }
+
+    /// Cleans up after peers that were disconnected, whoever did it.
+    fn remove_closed(&mut self) {
+        let torrent = self.torrent;
+        let closed: Vec<Token> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.closed)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in closed {
+            let mut peer = self.peers.remove(&token).unwrap();
+            peer.deregister(self.poll.registry());
+            if !peer.is_open() {
+                debug!(
+                    target: "peer",
+                    addr:% = peer.addr;
+                    "Connection failed: {}", peer.error.as_deref().unwrap_or("closed")
+                );
+                continue;
+            }
+            debug!(
+                target: "peer",
+                info_hash:% = torrent.torrent_file.info_hash.as_string(),
+                addr:% = peer.addr;
+                "Disconnected"
+            );
+            self.changed = true;
+            torrent.content.cancel_peer(&peer.id);
+            if peer.violated {
+                torrent.add_violation(peer.addr.ip());
+            }
+            torrent.content.emit(EventKind::PeerDisconnected {
+                addr: peer.addr,
+                id: peer.id,
+                error: peer.error,
+            });
+        }
+    }
+
+    /// Tells the torrent who is connected, for `peer_stats`.
+    fn publish(&mut self) {
+        if !self.changed {
+            return;
+        }
+        self.changed = false;
+        *self.torrent.peers.lock().unwrap() = self
+            .peers
+            .values()
+            .filter(|peer| peer.is_open())
+            .map(|peer| (peer.addr, peer.id, peer.stats.clone()))
+            .collect();
+    }
+}
+
+impl Drop for Swarm<'_> {
+    fn drop(&mut self) {
+        *self.torrent.incoming.lock().unwrap() = None;
+        *self.torrent.waker.lock().unwrap() = None;
+    }
+}
+
+/// Reads, handles and writes what one peer is ready for, true if its
+/// handshake came in just now. Pieces it completed go to `finished`.
+fn serve(torrent: &Torrent, peer: &mut Peer, now: Instant, finished: &mut Vec<u32>) -> bool {
+    if peer.closed {
+        return false;
+    }
+    let was_open = peer.is_open();
+    let result = peer.receive(now).map(|messages| {
+        //the bitfield has to come before anything else we send
+        if !was_open && peer.is_open() {
+            send_bitfield(torrent, peer);
+        }
+        for message in messages {
+            handle(torrent, peer, message, finished);
+        }
+        upload(torrent, peer);
+    });
+    let result = result
+        .and_then(|_| peer.check_timers(now))
+        .and_then(|_| peer.flush(now));
+    if let Err(e) = result {
+        //a peer that just left isn't worth a warning
+        let level = match e.is_violation() && peer.is_open() {
+            true => Level::Warn,
+            false => Level::Debug,
+        };
+        log!(target: "peer", level, addr:% = peer.addr; "Dropping peer: {}", e);
+        peer.violated = e.is_violation();
+        peer.close(Some(e.to_string()));
+        return false;
+    }
+    let opened = !was_open && peer.is_open();
+    if opened {
+        let client = peer.try_parse_client();
+        debug!(
+            target: "peer",
+            info_hash:% = torrent.torrent_file.info_hash.as_string(),
+            addr:% = peer.addr,
+            peer:% = peer.id_string(),
+            client:%;
+            "Handshake done"
+        );
+        torrent.content.emit(EventKind::PeerConnected {
+            addr: peer.addr,
+            id: peer.id,
+            client,
+        });
+    }
+    opened
+}
+
+/// Right after the handshake, if we have anything at all.
+fn send_bitfield(torrent: &Torrent, peer: &mut Peer) {
+    let bitfield = torrent.content.get_bitfield();
+    if bitfield.iter().any(|byte| *byte != 0) {
+        peer.send(PeerMessage::Bitfield(bitfield));
+    }
+}
+
+fn unchoke(peer: &mut Peer) {
+    if peer.am_choking {
+        peer.send(PeerMessage::Unchoke);
+        peer.am_choking = false;
+    }
+}
+
+/// Answers the peer's requests while little is waiting to be written, the
+/// rest wait for the socket and the rate limiters.
+fn upload(torrent: &Torrent, peer: &mut Peer) {
+    while peer.has_room() {
+        let Some((index, begin, length)) = peer.their_requests.pop_front() else {
+            break;
+        };
+        match torrent.content.read_block(index as usize, begin, length) {
+            Some(block) => peer.send(PeerMessage::Piece(index, begin, block)),
+            None => debug!(
+                target: "peer",
+                addr:% = peer.addr;
+                "Can't send block {} {} {}", index, begin, length
+            ),
+        }
+    }
+}
+
+fn handle(torrent: &Torrent, peer: &mut Peer, message: PeerMessage, finished: &mut Vec<u32>) {
+    //dropped already, the rest of what it sent doesn't matter
+    if peer.closed {
+        return;
+    }
+    let content = &torrent.content;
+    match message {
+        PeerMessage::KeepAlive => (),
+        PeerMessage::Choke => {
+            debug!(target: "peer", addr:% = peer.addr; "Choked");
+            peer.peer_choking = true;
+            //a choking peer drops what it was asked, someone else gets asked
+            if let Some((piece, _)) = peer.requested.take() {
+                content.cancel_piece(piece as usize, &peer.id);
+            }
+        }
+        PeerMessage::Unchoke => {
+            debug!(target: "peer", addr:% = peer.addr; "Unchoked");
+            peer.peer_choking = false;
+        }
+        PeerMessage::Interested => {
+            trace!(target: "peer", addr:% = peer.addr; "Interested");
+            peer.peer_interested = true;
+            //everyone who asks gets served, the rate limiters share it out
+            unchoke(peer);
+        }
+        PeerMessage::NotInterested => {
+            trace!(target: "peer", addr:% = peer.addr; "Not interested");
+            peer.peer_interested = false;
+        }
+        PeerMessage::Have(index) => peer.add_piece_to_bitfield(index),
+        PeerMessage::Bitfield(field) => {
+            trace!(target: "peer", addr:% = peer.addr; "Bitfield {:?}", field);
+            peer.bitfield = field;
+        }
+        PeerMessage::Request(index, begin, length) => {
+            trace!(target: "peer", addr:% = peer.addr; "Request {} {} {}", index, begin, length);
+            //requests that come in while choked are dropped, BEP 3
+            if peer.am_choking
+                || length == 0
+                || length > BLOCK_SIZE
+                || peer.their_requests.len() >= MAX_THEIR_REQUESTS
+            {
+                return;
+            }
+            peer.their_requests.push_back((index, begin, length));
+        }
+        PeerMessage::Piece(index, begin, block) => {
+            add_block(torrent, peer, index, begin, &block, finished)
+        }
+        PeerMessage::Cancel(index, begin, length) => {
+            trace!(target: "peer", addr:% = peer.addr; "Cancel {} {} {}", index, begin, length);
+            peer.their_requests.retain(|request| *request != (index, begin, length));
+        }
+        PeerMessage::Port(port) => {
+            trace!(target: "peer", addr:% = peer.addr; "DHT port {}", port)
+        }
+    }
+}
+
+fn add_block(
+    torrent: &Torrent,
+    peer: &mut Peer,
+    index: u32,
+    begin: u32,
+    block: &[u8],
+    finished: &mut Vec<u32>,
+) {
+    let content = &torrent.content;
+    match content.add_block(index as usize, begin as usize, block, &peer.id, peer.addr.ip()) {
+        Err(e) => {
+            peer.stats.add_redundant(block.len() as u64);
+            torrent.stats.add_redundant(block.len() as u64);
+            if !e.is_violation() {
+                debug!(target: "peer", addr:% = peer.addr; "Ignoring block: {}", e);
+                return;
+            }
+            warn!(target: "peer", addr:% = peer.addr; "Dropping peer: {}", e);
+            peer.violated = true;
+            peer.close(Some(e.to_string()));
+        }
+        Ok(Some(true)) => {
+            debug!(target: "peer", addr:% = peer.addr; "Piece {} done", index);
+            peer.requested = None;
+            finished.push(index);
+            torrent.ban_corrupt_peers(index);
+            if content.is_finished() {
+                content.finalize_files();
+                content.emit(EventKind::TorrentFinished);
+            }
+        }
+        Ok(Some(false)) => {
+            let size = content.pieces[index as usize].lock().unwrap().size as u64;
+            peer.stats.add_corrupt(size);
+            torrent.stats.add_corrupt(size);
+            warn!(target: "peer", addr:% = peer.addr; "Hash doesn't match, piece {}", index);
+            peer.requested = None;
+            torrent.ban_corrupt_peers(index);
+        }
+        //the piece is still coming, its timer starts over
+        Ok(None) => {
+            if let Some((_, since)) = peer.requested.as_mut().filter(|(piece, _)| *piece == index) {
+                *since = Instant::now();
+            }
+        }
+    }
+}
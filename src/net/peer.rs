@@ -0,0 +1,582 @@
+//! One peer connection, driven by the torrent's event loop.
+//!
+//! Nothing here blocks. Bytes are read into a buffer and split into
+//! messages once they are whole, messages to send wait in a queue until the
+//! socket takes them, and every connection keeps the timers that decide
+//! when it is given up or needs a keep-alive.
+use crate::content::PeerId;
+use crate::ratelimit::{self, RateLimits};
+use crate::wire::{self, PeerError, PeerMessage};
+use crate::{ConnectionSlot, Torrent, TransferStats, BLOCK_SIZE};
+use log::trace;
+use mio::event::Event;
+use mio::net::TcpStream;
+use mio::{Interest, Registry, Token};
+use rand::Rng;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// From starting to connect until their handshake is in.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// A peer that sends nothing for this long, not even a keep-alive, is dropped.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+/// Ours go out after this long without sending anything.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+/// A peer gets this long for each block of the piece it was asked for.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// Read or written per turn of the loop, a fast peer can't hold up the rest.
+const TURN_BYTES: usize = 4 * BLOCK_SIZE as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    //waiting for the socket to become writable
+    Connecting,
+    //ours is queued, waiting for theirs
+    Handshake,
+    Open,
+}
+
+#[derive(Debug)]
+pub(crate) struct Peer {
+    pub(crate) id: PeerId,
+    pub(crate) addr: SocketAddr,
+    stream: TcpStream,
+    stage: Stage,
+    piece_count: u32,
+    info_hash: [u8; 20],
+    //read but not a whole message yet
+    read_buf: Vec<u8>,
+    //queued, sent up to `sent`
+    write_buf: Vec<u8>,
+    sent: usize,
+    //(overhead, payload) bytes of each queued message not written yet,
+    //counted as they go out
+    unsent: VecDeque<(usize, usize)>,
+    //readiness mio reported and we haven't used up, events are edge triggered
+    readable: bool,
+    writable: bool,
+    //held off by the rate limiters until then
+    read_after: Instant,
+    write_after: Instant,
+    started: Instant,
+    last_read: Instant,
+    last_write: Instant,
+    pub(crate) bitfield: Vec<u8>,
+    pub(crate) am_interested: bool,
+    pub(crate) am_choking: bool,
+    pub(crate) peer_choking: bool,
+    pub(crate) peer_interested: bool,
+    //(piece, offset, length) they asked us for, oldest first
+    pub(crate) their_requests: VecDeque<(u32, u32, u32)>,
+    //the piece asked for and when its last block came
+    pub(crate) requested: Option<(u32, Instant)>,
+    //the loop cleans up after it
+    pub(crate) closed: bool,
+    //dropped for breaking the protocol
+    pub(crate) violated: bool,
+    //why the connection broke, none if we closed it
+    pub(crate) error: Option<String>,
+    //the torrent's and the global ones
+    limits: [Arc<RateLimits>; 2],
+    pub(crate) stats: Arc<TransferStats>,
+    torrent_stats: Arc<TransferStats>,
+    _slot: ConnectionSlot,
+}
+
+impl Peer {
+    /// Starts connecting, our handshake goes out once it is through.
+    pub(crate) fn connect(
+        torrent: &Torrent,
+        addr: SocketAddr,
+        slot: ConnectionSlot,
+    ) -> io::Result<Peer> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Peer::new(torrent, stream, addr, slot, Stage::Connecting))
+    }
+
+    /// A connection the session accepted, it has read their handshake.
+    pub(crate) fn accept(
+        torrent: &Torrent,
+        stream: std::net::TcpStream,
+        addr: SocketAddr,
+        id: PeerId,
+        slot: ConnectionSlot,
+    ) -> io::Result<Peer> {
+        stream.set_nonblocking(true)?;
+        let mut peer = Peer::new(
+            torrent,
+            TcpStream::from_std(stream),
+            addr,
+            slot,
+            Stage::Open,
+        );
+        peer.id = id;
+        //their handshake, the session read it
+        peer.count_download(0, 68);
+        Ok(peer)
+    }
+
+    fn new(
+        torrent: &Torrent,
+        stream: TcpStream,
+        addr: SocketAddr,
+        slot: ConnectionSlot,
+        stage: Stage,
+    ) -> Peer {
+        let now = Instant::now();
+        let info_hash = *torrent.torrent_file.info_hash.raw();
+        let piece_count = torrent.torrent_file.info.piece_count;
+        Peer {
+            id: [0; 20],
+            addr,
+            stream,
+            stage,
+            piece_count,
+            info_hash,
+            read_buf: vec![],
+            write_buf: Handshake::new(&info_hash).raw.to_vec(),
+            sent: 0,
+            unsent: VecDeque::from([(68, 0)]),
+            readable: false,
+            writable: false,
+            read_after: now,
+            write_after: now,
+            started: now,
+            last_read: now,
+            last_write: now,
+            bitfield: vec![0; wire::bitfield_len(piece_count)],
+            am_interested: false,
+            am_choking: true,
+            peer_choking: true,
+            peer_interested: false,
+            their_requests: VecDeque::new(),
+            requested: None,
+            closed: false,
+            violated: false,
+            error: None,
+            limits: [
+                Arc::clone(&torrent.rate_limits),
+                Arc::clone(&torrent.global_rate_limits),
+            ],
+            stats: Arc::new(TransferStats::new()),
+            torrent_stats: Arc::clone(&torrent.stats),
+            _slot: slot,
+        }
+    }
+
+    pub(crate) fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        registry.register(
+            &mut self.stream,
+            token,
+            Interest::READABLE | Interest::WRITABLE,
+        )
+    }
+
+    pub(crate) fn deregister(&mut self, registry: &Registry) {
+        let _ = registry.deregister(&mut self.stream);
+    }
+
+    /// Remembers what the socket is ready for until it is used up.
+    pub(crate) fn ready(&mut self, event: &Event) {
+        //errors and hangups show up on the next read or write
+        if event.is_readable() || event.is_read_closed() || event.is_error() {
+            self.readable = true;
+        }
+        if event.is_writable() || event.is_write_closed() || event.is_error() {
+            self.writable = true;
+        }
+    }
+
+    /// Handshakes exchanged, messages can flow.
+    pub(crate) fn is_open(&self) -> bool {
+        self.stage == Stage::Open
+    }
+
+    /// Open, not waiting for a piece and not told we are interested while
+    /// still choked.
+    pub(crate) fn is_idle(&self) -> bool {
+        self.is_open()
+            && !self.closed
+            && self.requested.is_none()
+            && !(self.peer_choking && self.am_interested)
+    }
+
+    /// Less than a turn's worth is waiting to be written, more can be queued
+    /// without piling up behind the rate limiters.
+    pub(crate) fn has_room(&self) -> bool {
+        self.write_buf.len() - self.sent < TURN_BYTES
+    }
+
+    /// Queues a message, it goes out on the next flush and is counted then.
+    pub(crate) fn send(&mut self, message: PeerMessage) {
+        let bytes = message.to_bytes();
+        let payload = match &message {
+            PeerMessage::Piece(_, _, block) => block.len(),
+            _ => 0,
+        };
+        self.unsent.push_back((bytes.len() - payload, payload));
+        self.write_buf.extend(bytes);
+    }
+
+    /// Reads what the socket has and returns the messages that are whole.
+    /// Their handshake is taken first, it isn't a message.
+    pub(crate) fn receive(&mut self, now: Instant) -> Result<Vec<PeerMessage>, PeerError> {
+        if self.stage == Stage::Connecting || !self.readable || now < self.read_after {
+            return Ok(vec![]);
+        }
+        let read = self.read_some()?;
+        if read > 0 {
+            self.last_read = now;
+            //what isn't read stays in the socket buffer and slows the peer down
+            let limiters = [&self.limits[0].download, &self.limits[1].download];
+            self.read_after = now + ratelimit::charge(&limiters, read);
+        }
+        if self.stage == Stage::Handshake {
+            if self.read_buf.len() < 68 {
+                return Ok(vec![]);
+            }
+            let theirs: Vec<u8> = self.read_buf.drain(..68).collect();
+            self.count_download(0, 68);
+            if theirs[0] != 19
+                || &theirs[1..20] != b"BitTorrent protocol"
+                || theirs[28..48] != self.info_hash
+            {
+                return Err(PeerError::InvalidHandshake);
+            }
+            self.id.copy_from_slice(&theirs[48..68]);
+            self.stage = Stage::Open;
+        }
+        let mut messages = vec![];
+        let mut taken = 0;
+        while let Some((message, len)) = wire::frame(&self.read_buf[taken..], self.piece_count)? {
+            let payload = match &message {
+                PeerMessage::Piece(_, _, block) => block.len(),
+                _ => 0,
+            };
+            self.count_download(payload, len - payload);
+            taken += len;
+            messages.push(message);
+        }
+        self.read_buf.drain(..taken);
+        Ok(messages)
+    }
+
+    /// Up to a turn's worth, the peer closing the connection is an error.
+    fn read_some(&mut self) -> io::Result<usize> {
+        let mut chunk = [0; BLOCK_SIZE as usize];
+        let mut read = 0;
+        while read < TURN_BYTES {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "closed by the peer",
+                    ))
+                }
+                Ok(n) => {
+                    self.read_buf.extend_from_slice(&chunk[..n]);
+                    read += n;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.readable = false;
+                    break;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(read)
+    }
+
+    /// Writes what is queued, as far as the socket and the rate limiters
+    /// let it. Finishes connecting first.
+    pub(crate) fn flush(&mut self, now: Instant) -> Result<(), PeerError> {
+        if !self.writable || now < self.write_after {
+            return Ok(());
+        }
+        if self.stage == Stage::Connecting {
+            //writable once the connection went through or failed
+            if let Some(e) = self.stream.take_error()? {
+                return Err(e.into());
+            }
+            match self.stream.peer_addr() {
+                Ok(_) => self.stage = Stage::Handshake,
+                Err(e) if e.kind() == ErrorKind::NotConnected => {
+                    self.writable = false;
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut written = 0;
+        while self.sent < self.write_buf.len() && written < TURN_BYTES {
+            let end = self.write_buf.len().min(self.sent + TURN_BYTES - written);
+            match self.stream.write(&self.write_buf[self.sent..end]) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero).into()),
+                Ok(n) => {
+                    self.sent += n;
+                    written += n;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.writable = false;
+                    break;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if self.sent == self.write_buf.len() {
+            self.write_buf.clear();
+            self.sent = 0;
+        }
+        if written > 0 {
+            self.count_written(written);
+            self.last_write = now;
+            let limiters = [&self.limits[0].upload, &self.limits[1].upload];
+            self.write_after = now + ratelimit::charge(&limiters, written);
+        }
+        Ok(())
+    }
+
+    /// Gives up on a peer that went quiet and keeps this side from looking
+    /// quiet to it.
+    pub(crate) fn check_timers(&mut self, now: Instant) -> Result<(), PeerError> {
+        let timed_out = |what: &str| PeerError::Io(io::Error::new(ErrorKind::TimedOut, what));
+        if !self.is_open() {
+            return match now.duration_since(self.started) > HANDSHAKE_TIMEOUT {
+                true => Err(timed_out("no handshake in time")),
+                false => Ok(()),
+            };
+        }
+        if now.duration_since(self.last_read) > IDLE_TIMEOUT {
+            return Err(timed_out("nothing received in too long"));
+        }
+        //only while we aren't holding off reading the blocks ourselves
+        let waiting = self.requested.filter(|_| now >= self.read_after);
+        if waiting.is_some_and(|(_, since)| now.duration_since(since) > REQUEST_TIMEOUT) {
+            return Err(timed_out("requested blocks never came"));
+        }
+        if self.write_buf.is_empty() && now.duration_since(self.last_write) > KEEP_ALIVE_INTERVAL {
+            trace!(target: "peer", addr:% = self.addr; "Sending keep-alive");
+            self.send(PeerMessage::KeepAlive);
+        }
+        Ok(())
+    }
+
+    /// When the loop has to come back to this peer without waiting for an
+    /// event, a rate limiter holding it off or a turn that wasn't enough.
+    pub(crate) fn wake_at(&self) -> Option<Instant> {
+        let read = (self.readable && self.stage != Stage::Connecting).then_some(self.read_after);
+        let pending = self.sent < self.write_buf.len() || !self.their_requests.is_empty();
+        let write = (self.writable && pending).then_some(self.write_after);
+        read.into_iter().chain(write).min()
+    }
+
+    /// `error` is why, none if we closed it.
+    pub(crate) fn close(&mut self, error: Option<String>) {
+        self.error = error;
+        self.closed = true;
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    fn count_download(&self, payload: usize, overhead: usize) {
+        self.stats.add_downloaded(payload as u64, overhead as u64);
+        self.torrent_stats
+            .add_downloaded(payload as u64, overhead as u64);
+    }
+
+    /// Counts `written` bytes that went out, the header of each message
+    /// before its payload.
+    fn count_written(&mut self, mut written: usize) {
+        let (mut payload, mut overhead) = (0, 0);
+        while written > 0 {
+            let Some(front) = self.unsent.front_mut() else {
+                break;
+            };
+            let header = front.0.min(written);
+            front.0 -= header;
+            overhead += header;
+            written -= header;
+            let body = front.1.min(written);
+            front.1 -= body;
+            payload += body;
+            written -= body;
+            if *front == (0, 0) {
+                self.unsent.pop_front();
+            }
+        }
+        self.count_upload(payload, overhead);
+    }
+
+    fn count_upload(&self, payload: usize, overhead: usize) {
+        self.stats.add_uploaded(payload as u64, overhead as u64);
+        self.torrent_stats
+            .add_uploaded(payload as u64, overhead as u64);
+    }
+
+    pub(crate) fn try_parse_client(&self) -> String {
+        let huh = [self.id[1], self.id[2]];
+
+        match &huh {
+            b"7T" => String::from("aTorrent for Android"),
+            b"AB" => String::from("AnyEvent::BitTorrent"),
+            b"AG" => String::from("Ares"),
+            b"A~" => String::from("Ares"),
+            b"AR" => String::from("Arctic"),
+            b"AV" => String::from("Avicora"),
+            b"AT" => String::from("Artemis"),
+            b"AX" => String::from("BitPump"),
+            b"AZ" => String::from("Azureus"),
+            b"BB" => String::from("BitBuddy"),
+            b"BC" => String::from("BitComet"),
+            b"BE" => String::from("Baretorrent"),
+            b"BF" => String::from("Bitflu"),
+            b"BG" => String::from("BTG (uses Rasterbar libtorrent)"),
+            b"BL" => String::from("BitCometLite (uses 6 digit version number) or BitBlinder"),
+            b"BP" => String::from("BitTorrent Pro (Azureus + spyware)"),
+            b"BR" => String::from("BitRocket"),
+            b"BS" => String::from("BTSlave"),
+            b"BT" => String::from("mainline BitTorrent (versions >= 7.9) or BBtor"),
+            b"Bt" => String::from("Bt"),
+            b"BW" => String::from("BitWombat"),
+            b"BX" => String::from("~Bittorrent X"),
+            b"CD" => String::from("Enhanced CTorrent"),
+            b"CT" => String::from("CTorrent"),
+            b"DE" => String::from("DelugeTorrent"),
+            b"DP" => String::from("Propagate Data Client"),
+            b"EB" => String::from("EBit"),
+            b"ES" => String::from("electric sheep"),
+            b"FC" => String::from("FileCroc"),
+            b"FD" => String::from("Free Download Manager (versions >= 5.1.12)"),
+            b"FT" => String::from("FoxTorrent"),
+            b"FX" => String::from("Freebox BitTorrent"),
+            b"GS" => String::from("GSTorrent"),
+            b"HK" => String::from("Hekate"),
+            b"HL" => String::from("Halite"),
+            b"HM" => String::from("hMule (uses Rasterbar libtorrent)"),
+            b"HN" => String::from("Hydranode"),
+            b"IL" => String::from("iLivid"),
+            b"JS" => String::from("Justseed.it client"),
+            b"JT" => String::from("JavaTorrent"),
+            b"KG" => String::from("KGet"),
+            b"KT" => String::from("KTorrent"),
+            b"LC" => String::from("LeechCraft"),
+            b"LH" => String::from("LH-ABC"),
+            b"LP" => String::from("Lphant"),
+            b"LT" => String::from("libtorrent"),
+            b"lt" => String::from("libTorrent"),
+            b"LW" => String::from("LimeWire"),
+            b"MK" => String::from("Meerkat"),
+            b"MO" => String::from("MonoTorrent"),
+            b"MP" => String::from("MooPolice"),
+            b"MR" => String::from("Miro"),
+            b"MT" => String::from("MoonlightTorrent"),
+            b"NB" => String::from("Net::BitTorrent"),
+            b"NX" => String::from("Net Transport"),
+            b"OS" => String::from("OneSwarm"),
+            b"OT" => String::from("OmegaTorrent"),
+            b"PB" => String::from("Protocol::BitTorrent"),
+            b"PD" => String::from("Pando"),
+            b"PI" => String::from("PicoTorrent"),
+            b"PT" => String::from("PHPTracker"),
+            b"qB" => String::from("qBittorrent"),
+            b"QD" => String::from("QQDownload"),
+            b"QT" => String::from("Qt 4 Torrent example"),
+            b"RT" => String::from("Retriever"),
+            b"RZ" => String::from("RezTorrent"),
+            b"S~" => String::from("Shareaza alpha/beta"),
+            b"SB" => String::from("~Swiftbit"),
+            b"SD" => String::from("Thunder (aka XùnLéi)"),
+            b"SM" => String::from("SoMud"),
+            b"SP" => String::from("BitSpirit"),
+            b"SS" => String::from("SwarmScope"),
+            b"ST" => String::from("SymTorrent"),
+            b"st" => String::from("sharktorrent"),
+            b"SZ" => String::from("Shareaza"),
+            b"TB" => String::from("Torch"),
+            b"TE" => String::from("terasaur Seed Bank"),
+            b"TL" => String::from("Tribler (versions >= 6.1.0)"),
+            b"TN" => String::from("TorrentDotNET"),
+            b"TR" => String::from("Transmission"),
+            b"TS" => String::from("Torrentstorm"),
+            b"TT" => String::from("TuoTu"),
+            b"UL" => String::from("uLeecher!"),
+            b"UM" => String::from("µTorrent for Mac"),
+            b"UT" => String::from("µTorrent"),
+            b"VG" => String::from("Vagaa"),
+            b"WD" => String::from("WebTorrent Desktop"),
+            b"WT" => String::from("BitLet"),
+            b"WW" => String::from("WebTorrent"),
+            b"WY" => String::from("FireTorrent"),
+            b"XF" => String::from("Xfplay"),
+            b"XL" => String::from("Xunlei"),
+            b"XS" => String::from("XSwifter"),
+            b"XT" => String::from("XanTorrent"),
+            b"XX" => String::from("Xtorrent"),
+            b"ZT" => String::from("ZipTorrent"),
+            b"ZO" => String::from("ZONA ?"),
+            _ => String::from("unknown client"),
+        }
+    }
+
+    pub(crate) fn id_string(&self) -> String {
+        format!(
+            "{}:{}.{}.{}.{}.{}.{}.{}.{}.{}.{}.{}.{}",
+            String::from_utf8_lossy(&self.id[..8]),
+            &self.id[8],
+            &self.id[9],
+            &self.id[10],
+            &self.id[11],
+            &self.id[12],
+            &self.id[13],
+            &self.id[14],
+            &self.id[15],
+            &self.id[16],
+            &self.id[17],
+            &self.id[18],
+            &self.id[19],
+        )
+    }
+
+    pub(crate) fn has_piece(&self, piece_number: usize) -> bool {
+        let byte = piece_number / 8;
+        let bit = (piece_number % 8) as u8;
+        self.bitfield[byte] & (0b10000000 >> bit) != 0
+    }
+
+    pub(crate) fn add_piece_to_bitfield(&mut self, piece_number: u32) {
+        let byte = piece_number / 8;
+        let bit = (piece_number % 8) as u8;
+        self.bitfield[byte as usize] |= 0b10000000 >> bit;
+    }
+}
+
+#[derive(Debug)]
+struct Handshake {
+    raw: [u8; 68],
+}
+
+impl Handshake {
+    fn new(info_hash: &[u8; 20]) -> Handshake {
+        let version = str::replace(env!("CARGO_PKG_VERSION"), ".", "");
+        let mut arr = vec![19];
+        arr.extend(b"BitTorrent protocol");
+        arr.extend([0, 0, 0, 0, 0, 0, 0, 0]);
+        arr.extend(info_hash);
+        arr.extend(b"-tT");
+        arr.extend(version.as_bytes());
+        arr.extend(b"R-");
+
+        let random_id: [u8; 12] = (0..12)
+            .map(|_| rand::thread_rng().gen_range(48..58))
+            .collect::<Vec<u8>>()
+            .try_into()
+            .unwrap();
+        arr.extend(&random_id);
+        let raw = arr.try_into().unwrap();
+        Handshake { raw }
+    }
+}
@@ -0,0 +1,105 @@
+//! Token bucket rate limiting.
+//!
+//! Every peer connection goes through the limiter of its torrent and the
+//! global one. Whoever moves bytes is charged right away and then holds off
+//! the connection until the debt is paid, so later callers wait behind
+//! earlier ones and nobody can starve the others.
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    //bytes per second, 0 is unlimited
+    rate: u64,
+    //negative while connections are paying off what they took
+    tokens: f64,
+    last: Instant,
+}
+
+/// A limiter for each direction.
+#[derive(Debug, Default)]
+pub struct RateLimits {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl RateLimiter {
+    /// `rate` is in bytes per second, 0 for no limit.
+    pub fn new(rate: u64) -> RateLimiter {
+        RateLimiter {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Takes effect for everyone, including callers already waiting.
+    pub fn set_rate(&self, rate: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.rate = rate;
+        //a burst of at most a second of the new rate
+        bucket.tokens = bucket.tokens.min(rate as f64);
+    }
+
+    /// Takes `bytes` from the bucket and returns how long to wait until
+    /// they are paid off.
+    fn take(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.rate == 0 {
+            return Duration::ZERO;
+        }
+        bucket.refill();
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-bucket.tokens / bucket.rate as f64)
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+    }
+}
+
+impl RateLimits {
+    pub fn new(download: u64, upload: u64) -> RateLimits {
+        RateLimits {
+            download: RateLimiter::new(download),
+            upload: RateLimiter::new(upload),
+        }
+    }
+}
+
+/// Charges every limiter for `bytes` and returns how long to hold off
+/// until the slowest of them is paid. Nothing sleeps, the caller keeps the
+/// connection idle that long.
+pub(crate) fn charge(limiters: &[&RateLimiter], bytes: usize) -> Duration {
+    limiters
+        .iter()
+        .map(|limiter| limiter.take(bytes))
+        .max()
+        .unwrap_or_default()
+}
@@ -0,0 +1,276 @@
+//! Fast resume data.
+//!
+//! Records which pieces are verified, what the files looked like when that
+//! was true, the blocks of pieces that were only partly downloaded and the
+//! file priorities. On the next start only files that changed since are
+//! hashed again.
+use crate::content::FilePriority;
+use bendy::decoding::{Error as DecodeError, FromBencode, Object, ResultExt};
+use bendy::encoding::{AsString, Error as EncodeError, SingleItemEncoder, ToBencode};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResumeData {
+    pub info_hash: [u8; 20],
+    pub destination: String,
+    pub bitfield: Vec<u8>,
+    pub files: Vec<FileState>,
+    pub priorities: Vec<FilePriority>,
+    pub partial_pieces: Vec<PartialPiece>,
+}
+
+/// Size and modification time of a file, a missing file is all zeros.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileState {
+    pub length: u64,
+    pub mtime: i64,
+    pub mtime_nanos: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PartialPiece {
+    pub piece: u32,
+    /// Offsets of the blocks that are present in `data`.
+    pub blocks: Vec<u32>,
+    pub data: Vec<u8>,
+}
+
+impl ResumeData {
+    /// Returns `None` if there is no resume data or it can't be read,
+    /// both just mean a full check.
+    pub fn load(path: &Path) -> Option<ResumeData> {
+        let raw = fs::read(path).ok()?;
+        ResumeData::from_bencode(&raw).ok()
+    }
+
+    /// Written to a temporary file first, so a crash can't leave half of it.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let raw = self
+            .to_bencode()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        if let Some(dir_path) = path.parent() {
+            fs::create_dir_all(dir_path)?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, raw)?;
+        fs::rename(tmp, path)
+    }
+
+    pub fn has_piece(&self, piece: usize) -> bool {
+        self.bitfield
+            .get(piece / 8)
+            .map(|byte| byte & (128 >> (piece % 8)) != 0)
+            .unwrap_or(false)
+    }
+}
+
+impl FileState {
+    pub fn read(path: &Path) -> FileState {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return FileState::default(),
+        };
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        FileState {
+            length: metadata.len(),
+            mtime: mtime.as_secs() as i64,
+            mtime_nanos: mtime.subsec_nanos(),
+        }
+    }
+}
+
+fn priority_to_int(priority: FilePriority) -> u8 {
+    match priority {
+        FilePriority::Skip => 0,
+        FilePriority::Low => 1,
+        FilePriority::Normal => 2,
+        FilePriority::High => 3,
+    }
+}
+
+fn priority_from_int(priority: u8) -> FilePriority {
+    match priority {
+        0 => FilePriority::Skip,
+        1 => FilePriority::Low,
+        3 => FilePriority::High,
+        _ => FilePriority::Normal,
+    }
+}
+
+impl ToBencode for ResumeData {
+    const MAX_DEPTH: usize = 4;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncodeError> {
+        //keys have to go in sorted order
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"bitfield", AsString(&self.bitfield))?;
+            e.emit_pair(b"destination", &self.destination)?;
+            e.emit_pair(b"files", &self.files)?;
+            e.emit_pair(b"info-hash", AsString(&self.info_hash))?;
+            e.emit_pair(b"partial", &self.partial_pieces)?;
+            let priorities = self
+                .priorities
+                .iter()
+                .map(|p| priority_to_int(*p))
+                .collect::<Vec<u8>>();
+            e.emit_pair(b"priorities", priorities)?;
+            Ok(())
+        })
+    }
+}
+
+impl FromBencode for ResumeData {
+    fn decode_bencode_object(object: Object) -> Result<Self, DecodeError> {
+        let mut info_hash = None;
+        let mut destination = None;
+        let mut bitfield = None;
+        let mut files = None;
+        let mut priorities = None;
+        let mut partial_pieces = None;
+
+        let mut dict = object.try_into_dictionary()?;
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"bitfield", value) => {
+                    bitfield = AsString::decode_bencode_object(value)
+                        .context("bitfield")
+                        .map(|b| Some(b.0))?;
+                }
+                (b"destination", value) => {
+                    destination = String::decode_bencode_object(value)
+                        .context("destination")
+                        .map(Some)?;
+                }
+                (b"files", value) => {
+                    files = Vec::<FileState>::decode_bencode_object(value)
+                        .context("files")
+                        .map(Some)?;
+                }
+                (b"info-hash", value) => {
+                    let hash = AsString::decode_bencode_object(value).context("info-hash")?;
+                    info_hash = Some(hash.0.try_into().map_err(|_| {
+                        DecodeError::unexpected_token("20 bytes", "some other length")
+                            .context("info-hash")
+                    })?);
+                }
+                (b"partial", value) => {
+                    partial_pieces = Vec::<PartialPiece>::decode_bencode_object(value)
+                        .context("partial")
+                        .map(Some)?;
+                }
+                (b"priorities", value) => {
+                    priorities = Vec::<u8>::decode_bencode_object(value)
+                        .context("priorities")
+                        .map(Some)?;
+                }
+                (_, _) => (),
+            }
+        }
+
+        Ok(ResumeData {
+            info_hash: info_hash.ok_or_else(|| DecodeError::missing_field("info-hash"))?,
+            destination: destination.ok_or_else(|| DecodeError::missing_field("destination"))?,
+            bitfield: bitfield.ok_or_else(|| DecodeError::missing_field("bitfield"))?,
+            files: files.ok_or_else(|| DecodeError::missing_field("files"))?,
+            priorities: priorities
+                .unwrap_or_default()
+                .into_iter()
+                .map(priority_from_int)
+                .collect(),
+            partial_pieces: partial_pieces.unwrap_or_default(),
+        })
+    }
+}
+
+impl ToBencode for FileState {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncodeError> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"length", self.length)?;
+            e.emit_pair(b"mtime", self.mtime)?;
+            e.emit_pair(b"mtime-nanos", self.mtime_nanos)?;
+            Ok(())
+        })
+    }
+}
+
+impl FromBencode for FileState {
+    fn decode_bencode_object(object: Object) -> Result<Self, DecodeError> {
+        let mut state = FileState::default();
+
+        let mut dict = object.try_into_dictionary()?;
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"length", value) => {
+                    state.length = u64::decode_bencode_object(value).context("length")?;
+                }
+                (b"mtime", value) => {
+                    state.mtime = i64::decode_bencode_object(value).context("mtime")?;
+                }
+                (b"mtime-nanos", value) => {
+                    state.mtime_nanos = u32::decode_bencode_object(value).context("mtime-nanos")?;
+                }
+                (_, _) => (),
+            }
+        }
+
+        Ok(state)
+    }
+}
+
+impl ToBencode for PartialPiece {
+    const MAX_DEPTH: usize = 2;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncodeError> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"blocks", &self.blocks)?;
+            e.emit_pair(b"data", AsString(&self.data))?;
+            e.emit_pair(b"piece", self.piece)?;
+            Ok(())
+        })
+    }
+}
+
+impl FromBencode for PartialPiece {
+    fn decode_bencode_object(object: Object) -> Result<Self, DecodeError> {
+        let mut piece = None;
+        let mut blocks = None;
+        let mut data = None;
+
+        let mut dict = object.try_into_dictionary()?;
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"blocks", value) => {
+                    blocks = Vec::<u32>::decode_bencode_object(value)
+                        .context("blocks")
+                        .map(Some)?;
+                }
+                (b"data", value) => {
+                    data = AsString::decode_bencode_object(value)
+                        .context("data")
+                        .map(|d| Some(d.0))?;
+                }
+                (b"piece", value) => {
+                    piece = u32::decode_bencode_object(value)
+                        .context("piece")
+                        .map(Some)?;
+                }
+                (_, _) => (),
+            }
+        }
+
+        Ok(PartialPiece {
+            piece: piece.ok_or_else(|| DecodeError::missing_field("piece"))?,
+            blocks: blocks.ok_or_else(|| DecodeError::missing_field("blocks"))?,
+            data: data.ok_or_else(|| DecodeError::missing_field("data"))?,
+        })
+    }
+}
@@ -0,0 +1,134 @@
+//! When to stop seeding.
+//!
+//! Goals are checked while the torrent runs with nothing left to download.
+//! The first one reached stops the torrent and `GoalAction` says what
+//! happens to it then.
+use crate::StatsSnapshot;
+use std::time::{Duration, Instant};
+
+/// Every goal is optional, the default seeds forever.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SeedGoals {
+    /// Payload uploaded over payload downloaded, or over the size of the
+    /// torrent when less than that was downloaded.
+    pub ratio: Option<f64>,
+    /// Counted from when the torrent started seeding in this run.
+    pub seed_time: Option<Duration>,
+    /// Time without uploading anything, also when no peer asks for it.
+    pub inactive_time: Option<Duration>,
+    pub action: GoalAction,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GoalAction {
+    /// Stops it, a session keeps it around paused.
+    #[default]
+    Pause,
+    /// Stops it and takes it out of its session.
+    Remove,
+    /// Also deletes the downloaded files and the resume data.
+    RemoveWithData,
+}
+
+/// Which goal was reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeedGoal {
+    Ratio,
+    SeedTime,
+    Inactivity,
+}
+
+/// Where a torrent is with its goals, from when it started seeding.
+#[derive(Debug)]
+pub(crate) struct Seeding {
+    started: Instant,
+    last_active: Instant,
+    uploaded: u64,
+}
+
+impl Seeding {
+    pub(crate) fn new(stats: &StatsSnapshot) -> Seeding {
+        Seeding {
+            started: Instant::now(),
+            last_active: Instant::now(),
+            uploaded: stats.payload_uploaded,
+        }
+    }
+
+    /// `size` is the length of the torrent in bytes.
+    pub(crate) fn check(
+        &mut self,
+        goals: &SeedGoals,
+        stats: &StatsSnapshot,
+        size: u64,
+    ) -> Option<SeedGoal> {
+        if stats.payload_uploaded > self.uploaded {
+            self.uploaded = stats.payload_uploaded;
+            self.last_active = Instant::now();
+        }
+        let downloaded = stats.payload_downloaded.max(size).max(1);
+        if goals
+            .ratio
+            .is_some_and(|ratio| stats.payload_uploaded as f64 / downloaded as f64 >= ratio)
+        {
+            return Some(SeedGoal::Ratio);
+        }
+        if goals
+            .seed_time
+            .is_some_and(|time| self.started.elapsed() >= time)
+        {
+            return Some(SeedGoal::SeedTime);
+        }
+        if goals
+            .inactive_time
+            .is_some_and(|time| self.last_active.elapsed() >= time)
+        {
+            return Some(SeedGoal::Inactivity);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(downloaded: u64, uploaded: u64) -> StatsSnapshot {
+        StatsSnapshot {
+            payload_downloaded: downloaded,
+            payload_uploaded: uploaded,
+            ..StatsSnapshot::default()
+        }
+    }
+
+    #[test]
+    fn ratio_counts_the_size_when_less_was_downloaded() {
+        let goals = SeedGoals {
+            ratio: Some(1.0),
+            ..SeedGoals::default()
+        };
+        let mut seeding = Seeding::new(&stats(0, 0));
+        assert_eq!(seeding.check(&goals, &stats(0, 999), 1000), None);
+        assert_eq!(
+            seeding.check(&goals, &stats(0, 1000), 1000),
+            Some(SeedGoal::Ratio)
+        );
+        assert_eq!(seeding.check(&goals, &stats(2000, 1000), 1000), None);
+    }
+
+    #[test]
+    fn uploading_resets_inactivity() {
+        let goals = SeedGoals {
+            inactive_time: Some(Duration::from_secs(60)),
+            ..SeedGoals::default()
+        };
+        let mut seeding = Seeding::new(&stats(0, 0));
+        seeding.last_active -= Duration::from_secs(61);
+        assert_eq!(seeding.check(&goals, &stats(0, 10), 1000), None);
+        seeding.last_active -= Duration::from_secs(61);
+        assert_eq!(
+            seeding.check(&goals, &stats(0, 10), 1000),
+            Some(SeedGoal::Inactivity)
+        );
+    }
+}
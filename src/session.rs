@@ -0,0 +1,564 @@
+//! Many torrents in one process.
+//!
+//! A `Session` listens on one port for all of its torrents and hands every
+//! incoming connection to the torrent whose info hash the peer asked for.
+//! Rate limits, the connection limit and the IP filter are shared by all of them.
+//!
+//! Torrents are queued. Only as many as `QueueLimits` allow run at once,
+//! the rest wait in queue order and start as slots free up.
+use crate::event::Subscribers;
+use crate::{
+    run_torrent, BlockedCounts, Error, Event, EventKind, GoalAction, InfoHash, IpFilter,
+    PeerSource, RateLimits, SeedGoals, ShutdownReport, Torrent,
+};
+use log::warn;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use threadpool::ThreadPool;
+
+/// How long an incoming connection gets to send its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the queue is looked at, besides after every change.
+const QUEUE_INTERVAL: Duration = Duration::from_secs(1);
+/// A torrent that just started has no rate yet, it can't be slow before this.
+const SLOW_GRACE: Duration = Duration::from_secs(60);
+
+pub struct Session {
+    torrents: Mutex<Queue>,
+    queue_limits: Mutex<QueueLimits>,
+    /// Every torrent of the session counts toward these.
+    pub rate_limits: Arc<RateLimits>,
+    //for torrents without goals of their own
+    seed_goals: Arc<Mutex<SeedGoals>>,
+    connection_limit: Arc<ConnectionLimit>,
+    //one for every torrent, replaced in place
+    ip_filter: Arc<RwLock<IpFilter>>,
+    //incoming connections refused before they said which torrent they want
+    blocked: BlockedCounts,
+    listen_port: u16,
+    shutting_down: AtomicBool,
+    events: Arc<Subscribers>,
+}
+
+/// How many torrents may be active at once. Torrents slower than the
+/// slow rates don't count, so a dead swarm can't hold a slot forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueLimits {
+    pub active_downloads: usize,
+    pub active_seeds: usize,
+    /// Downloads and seeds together.
+    pub active_limit: usize,
+    /// Bytes per second.
+    pub slow_download_rate: u64,
+    pub slow_upload_rate: u64,
+}
+
+#[derive(Default)]
+struct Queue {
+    entries: HashMap<[u8; 20], Entry>,
+    //queue position is the index
+    order: Vec<[u8; 20]>,
+}
+
+struct Entry {
+    torrent: Arc<Torrent>,
+    //None while queued
+    thread: Option<JoinHandle<Result<ShutdownReport, Error>>>,
+    started: Instant,
+    //paused by the app, the queue leaves it alone
+    paused: bool,
+    //told to stop, the thread may still be finishing
+    stopping: bool,
+    //run returned without being told to, not started again until resumed
+    failed: bool,
+}
+
+/// Caps the number of peer connections, shared by whoever holds it.
+#[derive(Debug)]
+pub struct ConnectionLimit {
+    max: AtomicUsize,
+    open: AtomicUsize,
+}
+
+/// One connection counted against a `ConnectionLimit`, freed when dropped.
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    limit: Arc<ConnectionLimit>,
+}
+
+impl Session {
+    /// Listens on `listen_port` on every interface, 0 picks a free port.
+    pub fn new(listen_port: u16) -> io::Result<Arc<Session>> {
+        let listener = TcpListener::bind(("0.0.0.0", listen_port))?;
+        //non blocking, so the thread notices the session is gone
+        listener.set_nonblocking(true)?;
+        let session = Arc::new(Session {
+            torrents: Mutex::new(Queue::default()),
+            queue_limits: Mutex::new(QueueLimits::default()),
+            rate_limits: Arc::new(RateLimits::default()),
+            seed_goals: Arc::new(Mutex::new(SeedGoals::default())),
+            connection_limit: Arc::new(ConnectionLimit::default()),
+            ip_filter: Arc::new(RwLock::new(IpFilter::new())),
+            blocked: BlockedCounts::default(),
+            listen_port: listener.local_addr()?.port(),
+            shutting_down: AtomicBool::new(false),
+            events: Arc::new(Subscribers::default()),
+        });
+        let weak = Arc::downgrade(&session);
+        thread::Builder::new()
+            .name("session listener".into())
+            .spawn(move || accept_connections(listener, weak))?;
+        let weak = Arc::downgrade(&session);
+        thread::Builder::new()
+            .name("session queue".into())
+            .spawn(move || manage_queue(weak))?;
+        Ok(session)
+    }
+
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port
+    }
+
+    /// Events of every torrent in the session from now on.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Connections of all torrents together.
+    pub fn set_connection_limit(&self, max: usize) {
+        self.connection_limit.set_max(max);
+    }
+
+    /// Replaces the filter of every torrent at once, running ones drop the
+    /// peers it blocks on their next turn.
+    pub fn set_ip_filter(&self, filter: IpFilter) {
+        *self.ip_filter.write().unwrap() = filter;
+    }
+
+    /// Incoming connections the filter refused before their handshake was
+    /// read. Each torrent counts the ones it refused itself.
+    pub fn blocked_connections(&self) -> &BlockedCounts {
+        &self.blocked
+    }
+
+    pub fn queue_limits(&self) -> QueueLimits {
+        *self.queue_limits.lock().unwrap()
+    }
+
+    pub fn set_queue_limits(&self, limits: QueueLimits) {
+        *self.queue_limits.lock().unwrap() = limits;
+        self.update_queue();
+    }
+
+    pub fn seed_goals(&self) -> SeedGoals {
+        *self.seed_goals.lock().unwrap()
+    }
+
+    /// Goals of every torrent that doesn't have its own.
+    pub fn set_seed_goals(&self, goals: SeedGoals) {
+        *self.seed_goals.lock().unwrap() = goals;
+    }
+
+    /// Puts the torrent at the end of the queue, it starts once there is a
+    /// slot for it. A torrent that is already in the session is returned
+    /// instead and the new one dropped.
+    pub fn add(&self, mut torrent: Torrent) -> Arc<Torrent> {
+        let key = *torrent.torrent_file.info_hash.raw();
+        let torrent = {
+            let mut queue = self.torrents.lock().unwrap();
+            if let Some(entry) = queue.entries.get(&key) {
+                return Arc::clone(&entry.torrent);
+            }
+            torrent.global_rate_limits = Arc::clone(&self.rate_limits);
+            torrent.global_seed_goals = Arc::clone(&self.seed_goals);
+            torrent.connection_limit = Arc::clone(&self.connection_limit);
+            torrent.ip_filter = Arc::clone(&self.ip_filter);
+            torrent.listen_port = self.listen_port;
+            torrent
+                .content
+                .set_session_events(Arc::clone(&self.events));
+            torrent.content.emit(EventKind::TorrentAdded);
+            let torrent = Arc::new(torrent);
+            queue.entries.insert(
+                key,
+                Entry {
+                    torrent: Arc::clone(&torrent),
+                    thread: None,
+                    started: Instant::now(),
+                    paused: false,
+                    stopping: false,
+                    failed: false,
+                },
+            );
+            queue.order.push(key);
+            torrent
+        };
+        self.update_queue();
+        torrent
+    }
+
+    /// Stops the torrent and takes it out of the session. Its data and
+    /// resume data stay where they are.
+    pub fn remove(&self, info_hash: &InfoHash) -> Option<Arc<Torrent>> {
+        let entry = {
+            let mut queue = self.torrents.lock().unwrap();
+            queue.order.retain(|key| key != info_hash.raw());
+            queue.entries.remove(info_hash.raw())?
+        };
+        //joined without the lock, the listener needs it meanwhile
+        stop(&entry.torrent, entry.thread);
+        self.update_queue();
+        Some(entry.torrent)
+    }
+
+    /// Stops the torrent and keeps the queue from starting it, false if it
+    /// isn't there. Returns right away, the torrent stops shortly after.
+    pub fn pause(&self, info_hash: &InfoHash) -> bool {
+        match self
+            .torrents
+            .lock()
+            .unwrap()
+            .entries
+            .get_mut(info_hash.raw())
+        {
+            Some(entry) => entry.paused = true,
+            None => return false,
+        }
+        self.update_queue();
+        true
+    }
+
+    /// Hands a paused or failed torrent back to the queue, false if it
+    /// isn't there.
+    pub fn resume(&self, info_hash: &InfoHash) -> bool {
+        match self
+            .torrents
+            .lock()
+            .unwrap()
+            .entries
+            .get_mut(info_hash.raw())
+        {
+            Some(entry) => {
+                entry.paused = false;
+                entry.failed = false;
+            }
+            None => return false,
+        }
+        self.update_queue();
+        true
+    }
+
+    pub fn queue_position(&self, info_hash: &InfoHash) -> Option<usize> {
+        self.torrents
+            .lock()
+            .unwrap()
+            .order
+            .iter()
+            .position(|key| key == info_hash.raw())
+    }
+
+    /// Moves the torrent to `position`, 0 being the front of the queue.
+    pub fn set_queue_position(&self, info_hash: &InfoHash, position: usize) -> bool {
+        {
+            let mut queue = self.torrents.lock().unwrap();
+            let Some(current) = queue.order.iter().position(|key| key == info_hash.raw()) else {
+                return false;
+            };
+            let key = queue.order.remove(current);
+            let position = position.min(queue.order.len());
+            queue.order.insert(position, key);
+        }
+        self.update_queue();
+        true
+    }
+
+    /// Whether the torrent is waiting for a slot.
+    pub fn is_queued(&self, info_hash: &InfoHash) -> bool {
+        self.torrents
+            .lock()
+            .unwrap()
+            .entries
+            .get(info_hash.raw())
+            .is_some_and(|entry| !entry.paused && !entry.failed && !entry.is_running())
+    }
+
+    pub fn torrent(&self, info_hash: &InfoHash) -> Option<Arc<Torrent>> {
+        self.torrents
+            .lock()
+            .unwrap()
+            .entries
+            .get(info_hash.raw())
+            .map(|entry| Arc::clone(&entry.torrent))
+    }
+
+    /// In queue order.
+    pub fn torrents(&self) -> Vec<Arc<Torrent>> {
+        let queue = self.torrents.lock().unwrap();
+        queue
+            .order
+            .iter()
+            .map(|key| Arc::clone(&queue.entries[key].torrent))
+            .collect()
+    }
+
+    /// Stops every torrent and the listener, returns a report for each
+    /// torrent that was running, in queue order. Ones that failed have
+    /// none, their error went to the `error` event. They all wind down at
+    /// once, the slowest tracker decides how long it takes.
+    pub fn shutdown(&self) -> Vec<ShutdownReport> {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        let mut queue = std::mem::take(&mut *self.torrents.lock().unwrap());
+        for entry in queue.entries.values().filter(|e| e.thread.is_some()) {
+            entry.torrent.stop();
+        }
+        queue
+            .order
+            .iter()
+            .filter_map(|key| queue.entries.remove(key)?.thread?.join().ok()?.ok())
+            .collect()
+    }
+
+    /// Starts and stops torrents so that the ones at the front of the queue
+    /// fill the slots.
+    fn update_queue(&self) {
+        let limits = self.queue_limits();
+        let mut queue = self.torrents.lock().unwrap();
+        let Queue { entries, order } = &mut *queue;
+        let (mut downloads, mut seeds) = (0, 0);
+        let mut done = vec![];
+        for key in order.iter() {
+            let entry = entries.get_mut(key).unwrap();
+            if entry.thread.as_ref().is_some_and(|t| t.is_finished()) {
+                //stopped itself, either for a seed goal or because it failed
+                if !entry.stopping {
+                    match entry.torrent.goal_reached() {
+                        Some((_, GoalAction::Pause)) => entry.paused = true,
+                        Some(_) => done.push(*key),
+                        None => entry.failed = true,
+                    }
+                }
+                entry.thread = None;
+                entry.stopping = false;
+            }
+            if done.last() == Some(key) {
+                continue;
+            }
+            if entry.paused || entry.failed {
+                entry.stop();
+                continue;
+            }
+            //on its way out, it will be started again once it is gone
+            if entry.stopping {
+                continue;
+            }
+            let seeding = entry.torrent.content.is_finished();
+            if entry.is_running() && entry.is_slow(seeding, &limits) {
+                continue;
+            }
+            let fits = downloads + seeds < limits.active_limit
+                && match seeding {
+                    true => seeds < limits.active_seeds,
+                    false => downloads < limits.active_downloads,
+                };
+            if !fits {
+                entry.stop();
+                continue;
+            }
+            match seeding {
+                true => seeds += 1,
+                false => downloads += 1,
+            }
+            entry.start();
+        }
+        //removed by their goal, their threads are finished already
+        order.retain(|key| !done.contains(key));
+        for key in done {
+            entries.remove(&key);
+        }
+    }
+
+    /// Hands an incoming connection to the torrent it asked for.
+    fn route(&self, mut stream: TcpStream, addr: SocketAddr) {
+        let mut handshake = [0u8; 68];
+        let _ = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT));
+        if stream.read_exact(&mut handshake).is_err()
+            || handshake[0] != 19
+            || &handshake[1..20] != b"BitTorrent protocol"
+        {
+            return;
+        }
+        let _ = stream.set_read_timeout(None);
+        let key: [u8; 20] = handshake[28..48].try_into().unwrap();
+        let torrent = match self.torrents.lock().unwrap().entries.get(&key) {
+            Some(entry) => Arc::clone(&entry.torrent),
+            None => return,
+        };
+        torrent.accept_peer(stream, addr, &handshake);
+    }
+}
+
+impl Entry {
+    fn is_running(&self) -> bool {
+        self.thread.is_some() && !self.stopping
+    }
+
+    fn is_slow(&self, seeding: bool, limits: &QueueLimits) -> bool {
+        if self.started.elapsed() < SLOW_GRACE {
+            return false;
+        }
+        let stats = self.torrent.stats();
+        match seeding {
+            true => stats.payload_upload_rate < limits.slow_upload_rate,
+            false => stats.payload_download_rate < limits.slow_download_rate,
+        }
+    }
+
+    fn start(&mut self) {
+        if self.thread.is_none() {
+            self.thread = Some(run_torrent(Arc::clone(&self.torrent)));
+            self.started = Instant::now();
+        }
+    }
+
+    /// Doesn't wait for it, update_queue notices once the thread is done.
+    fn stop(&mut self) {
+        if self.thread.is_some() && !self.stopping {
+            self.torrent.stop();
+            self.stopping = true;
+        }
+    }
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        QueueLimits {
+            active_downloads: 3,
+            active_seeds: 5,
+            active_limit: 8,
+            slow_download_rate: 2048,
+            slow_upload_rate: 2048,
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Session on port {} with {} torrents",
+            self.listen_port,
+            self.torrents.lock().unwrap().entries.len()
+        )
+    }
+}
+
+/// A torrent that isn't running is left alone, a stop would end its next run.
+fn stop(torrent: &Torrent, thread: Option<JoinHandle<Result<ShutdownReport, Error>>>) {
+    if let Some(thread) = thread {
+        torrent.stop();
+        let _ = thread.join();
+    }
+}
+
+fn manage_queue(session: Weak<Session>) {
+    loop {
+        thread::sleep(QUEUE_INTERVAL);
+        let Some(s) = session.upgrade() else {
+            break;
+        };
+        if s.shutting_down.load(Ordering::Relaxed) {
+            break;
+        }
+        s.update_queue();
+    }
+}
+
+fn accept_connections(listener: TcpListener, session: Weak<Session>) {
+    //handshakes are read off the listener thread, a slow peer can't hold up the rest
+    let pool = ThreadPool::new(4);
+    loop {
+        let Some(s) = session.upgrade() else {
+            break;
+        };
+        if s.shutting_down.load(Ordering::Relaxed) {
+            break;
+        }
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                //refused before a thread waits for their handshake
+                if s.ip_filter.read().unwrap().is_blocked(addr.ip()) {
+                    s.blocked.add(PeerSource::Incoming);
+                    continue;
+                }
+                if stream.set_nonblocking(false).is_ok() {
+                    pool.execute(move || s.route(stream, addr));
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                drop(s);
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(e) => {
+                warn!(target: "peer", "Couldn't accept connection: {}", e);
+                drop(s);
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            max: AtomicUsize::new(max),
+            open: AtomicUsize::new(0),
+        }
+    }
+
+    /// Connections that are open already stay open when lowering it.
+    pub fn set_max(&self, max: usize) {
+        self.max.store(max, Ordering::Relaxed);
+    }
+
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// `None` if the limit is reached.
+    pub fn acquire(self: &Arc<Self>) -> Option<ConnectionSlot> {
+        let max = self.max.load(Ordering::Relaxed);
+        self.open
+            .fetch_update(Ordering::AcqRel, Ordering::Relaxed, |open| {
+                (open < max).then_some(open + 1)
+            })
+            .ok()?;
+        Some(ConnectionSlot {
+            limit: Arc::clone(self),
+        })
+    }
+}
+
+impl Default for ConnectionLimit {
+    /// No limit at all.
+    fn default() -> Self {
+        Self::new(usize::MAX)
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.limit.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,113 @@
+//! What a torrent is doing and what it was asked to do.
+//!
+//! Both are kept in atomics, so the state can be asked for from any thread
+//! without locking the torrent.
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TorrentState {
+    /// Not running, also before the first run.
+    Stopped,
+    /// Hashing what is on disk, or checking it against resume data.
+    Checking,
+    /// Fetching the info dictionary from peers, for magnet links. Those
+    /// aren't supported yet, so nothing gets here.
+    DownloadingMetadata,
+    Downloading,
+    /// Everything wanted is there.
+    Seeding,
+    /// Verified pieces are kept, no peers are connected.
+    Paused,
+    /// The run ended on a failure, see `Torrent::error`.
+    Error,
+}
+
+impl TorrentState {
+    pub fn can_change_to(self, to: TorrentState) -> bool {
+        use TorrentState::*;
+        match (self, to) {
+            //every state can fail, and stop unless it did
+            (Error, Stopped) => true,
+            (_, Error) => true,
+            (_, Stopped) => true,
+            (Stopped | Error, Checking | DownloadingMetadata) => true,
+            (DownloadingMetadata, Checking | Paused) => true,
+            (Checking, Downloading | Seeding | Paused) => true,
+            //a file that stopped being skipped makes a seed download again
+            (Downloading, Seeding | Paused) => true,
+            (Seeding, Downloading | Paused) => true,
+            (Paused, Downloading | Seeding) => true,
+            _ => false,
+        }
+    }
+
+    fn from_u8(state: u8) -> TorrentState {
+        use TorrentState::*;
+        [
+            Stopped,
+            Checking,
+            DownloadingMetadata,
+            Downloading,
+            Seeding,
+            Paused,
+            Error,
+        ][state as usize]
+    }
+}
+
+/// Holds a `TorrentState`.
+#[derive(Debug)]
+pub(crate) struct AtomicState(AtomicU8);
+
+impl AtomicState {
+    pub(crate) fn new(state: TorrentState) -> AtomicState {
+        AtomicState(AtomicU8::new(state as u8))
+    }
+
+    pub(crate) fn get(&self) -> TorrentState {
+        TorrentState::from_u8(self.0.load(Ordering::Acquire))
+    }
+
+    /// Only the thread running the torrent changes it.
+    pub(crate) fn set(&self, state: TorrentState) {
+        self.0.store(state as u8, Ordering::Release);
+    }
+}
+
+/// What the application asked the running torrent to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum Control {
+    Run,
+    Pause,
+    Stop,
+}
+
+#[derive(Debug)]
+pub(crate) struct AtomicControl(AtomicU8);
+
+impl AtomicControl {
+    pub(crate) fn new(control: Control) -> AtomicControl {
+        AtomicControl(AtomicU8::new(control as u8))
+    }
+
+    pub(crate) fn get(&self) -> Control {
+        match self.0.load(Ordering::Acquire) {
+            0 => Control::Run,
+            1 => Control::Pause,
+            _ => Control::Stop,
+        }
+    }
+
+    pub(crate) fn set(&self, control: Control) {
+        self.0.store(control as u8, Ordering::Release);
+    }
+
+    /// Changes it only if it is `from`.
+    pub(crate) fn replace(&self, from: Control, to: Control) -> bool {
+        self.0
+            .compare_exchange(from as u8, to as u8, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+}
@@ -0,0 +1,176 @@
+//! Byte accounting for peers and torrents.
+//!
+//! Counters are atomics and rates are kept per second over a short window,
+//! so taking a snapshot is cheap enough to poll from a UI every frame.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Seconds the rates are averaged over.
+const RATE_WINDOW: usize = 5;
+
+#[derive(Debug)]
+pub struct TransferStats {
+    payload_downloaded: AtomicU64,
+    payload_uploaded: AtomicU64,
+    overhead_downloaded: AtomicU64,
+    overhead_uploaded: AtomicU64,
+    wasted_corrupt: AtomicU64,
+    wasted_redundant: AtomicU64,
+    payload_download_rate: Rolling,
+    payload_upload_rate: Rolling,
+    download_rate: Rolling,
+    upload_rate: Rolling,
+    started: Instant,
+}
+
+/// Everything in bytes and bytes per second, since the stats were created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub payload_downloaded: u64,
+    pub payload_uploaded: u64,
+    /// Message headers and every message that isn't piece data.
+    pub overhead_downloaded: u64,
+    pub overhead_uploaded: u64,
+    /// Pieces that failed the hash check.
+    pub wasted_corrupt: u64,
+    /// Blocks that were refused, mostly ones received twice.
+    pub wasted_redundant: u64,
+    pub payload_download_rate: u64,
+    pub payload_upload_rate: u64,
+    /// Payload and overhead together.
+    pub download_rate: u64,
+    pub upload_rate: u64,
+}
+
+//bytes of the last RATE_WINDOW seconds, one slot per second
+#[derive(Debug)]
+struct Rolling {
+    slots: Mutex<([u64; RATE_WINDOW + 1], u64)>,
+}
+
+impl TransferStats {
+    pub fn new() -> TransferStats {
+        TransferStats {
+            payload_downloaded: AtomicU64::new(0),
+            payload_uploaded: AtomicU64::new(0),
+            overhead_downloaded: AtomicU64::new(0),
+            overhead_uploaded: AtomicU64::new(0),
+            wasted_corrupt: AtomicU64::new(0),
+            wasted_redundant: AtomicU64::new(0),
+            payload_download_rate: Rolling::new(),
+            payload_upload_rate: Rolling::new(),
+            download_rate: Rolling::new(),
+            upload_rate: Rolling::new(),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn add_downloaded(&self, payload: u64, overhead: u64) {
+        let second = self.second();
+        self.payload_downloaded
+            .fetch_add(payload, Ordering::Relaxed);
+        self.overhead_downloaded
+            .fetch_add(overhead, Ordering::Relaxed);
+        self.payload_download_rate.add(second, payload);
+        self.download_rate.add(second, payload + overhead);
+    }
+
+    pub fn add_uploaded(&self, payload: u64, overhead: u64) {
+        let second = self.second();
+        self.payload_uploaded.fetch_add(payload, Ordering::Relaxed);
+        self.overhead_uploaded
+            .fetch_add(overhead, Ordering::Relaxed);
+        self.payload_upload_rate.add(second, payload);
+        self.upload_rate.add(second, payload + overhead);
+    }
+
+    pub fn add_corrupt(&self, bytes: u64) {
+        self.wasted_corrupt.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_redundant(&self, bytes: u64) {
+        self.wasted_redundant.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let second = self.second();
+        StatsSnapshot {
+            payload_downloaded: self.payload_downloaded.load(Ordering::Relaxed),
+            payload_uploaded: self.payload_uploaded.load(Ordering::Relaxed),
+            overhead_downloaded: self.overhead_downloaded.load(Ordering::Relaxed),
+            overhead_uploaded: self.overhead_uploaded.load(Ordering::Relaxed),
+            wasted_corrupt: self.wasted_corrupt.load(Ordering::Relaxed),
+            wasted_redundant: self.wasted_redundant.load(Ordering::Relaxed),
+            payload_download_rate: self.payload_download_rate.rate(second),
+            payload_upload_rate: self.payload_upload_rate.rate(second),
+            download_rate: self.download_rate.rate(second),
+            upload_rate: self.upload_rate.rate(second),
+        }
+    }
+
+    fn second(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+}
+
+impl Default for TransferStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatsSnapshot {
+    pub fn downloaded(&self) -> u64 {
+        self.payload_downloaded + self.overhead_downloaded
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.payload_uploaded + self.overhead_uploaded
+    }
+
+    pub fn wasted(&self) -> u64 {
+        self.wasted_corrupt + self.wasted_redundant
+    }
+}
+
+impl Rolling {
+    fn new() -> Rolling {
+        Rolling {
+            slots: Mutex::new(([0; RATE_WINDOW + 1], 0)),
+        }
+    }
+
+    fn add(&self, second: u64, bytes: u64) {
+        let mut slots = self.slots.lock().unwrap();
+        advance(&mut slots, second);
+        slots.0[second as usize % (RATE_WINDOW + 1)] += bytes;
+    }
+
+    /// Average of the last full seconds, the current one is still filling up.
+    fn rate(&self, second: u64) -> u64 {
+        let mut slots = self.slots.lock().unwrap();
+        advance(&mut slots, second);
+        let current = second as usize % (RATE_WINDOW + 1);
+        let total: u64 = slots
+            .0
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != current)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        //right after the start there aren't RATE_WINDOW seconds to average yet
+        let seconds = second.clamp(1, RATE_WINDOW as u64);
+        total / seconds
+    }
+}
+
+/// Empties the slots of the seconds that went by without anything added.
+fn advance(slots: &mut ([u64; RATE_WINDOW + 1], u64), second: u64) {
+    let (slots, last) = slots;
+    let passed = second.saturating_sub(*last).min(RATE_WINDOW as u64 + 1);
+    for s in 1..=passed {
+        slots[((*last + s) % (RATE_WINDOW as u64 + 1)) as usize] = 0;
+    }
+    *last = (*last).max(second);
+}
@@ -0,0 +1,51 @@
+//! Where the content of a torrent actually lives.
+//!
+//! `Content` addresses data by file index and offset and leaves the rest to a
+//! `Storage`. `FileStorage` is the default and writes to the download folder,
+//! `MmapStorage` serves it from memory mapped windows of the same files and
+//! `MemoryStorage` keeps everything in RAM.
+use crate::resume::FileState;
+use std::fmt::Debug;
+use std::io;
+use std::path::Path;
+mod file;
+mod memory;
+mod mmap;
+pub use file::{FileStorage, DEFAULT_OPEN_FILE_LIMIT};
+pub use memory::MemoryStorage;
+pub use mmap::MmapStorage;
+
+pub trait Storage: Debug + Send + Sync {
+    /// Fills `buf` with the data at `offset`, failing if not all of it is there.
+    fn read(&self, file: usize, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    fn write(&self, file: usize, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Makes the file exist with the given length. Must leave a file that
+    /// already has that length untouched, resume data relies on it.
+    fn preallocate(&self, file: usize, length: u64) -> io::Result<()>;
+
+    /// Makes everything written so far durable.
+    fn flush(&self) -> io::Result<()>;
+
+    /// Flushes and lets go of every open file, for when the torrent stops.
+    /// The storage still works afterwards and reopens files as needed.
+    fn close(&self) -> io::Result<()> {
+        self.flush()
+    }
+
+    /// Caps the number of files held open at once, where that applies.
+    fn set_open_file_limit(&self, _limit: usize) {}
+
+    fn rename(&self, file: usize, to: &Path) -> io::Result<()>;
+
+    fn delete(&self, file: usize) -> io::Result<()>;
+
+    /// Size and modification time, all zeros for a file that doesn't exist.
+    fn file_state(&self, file: usize) -> FileState;
+
+    /// Called once the file is complete, to apply BEP 47 attributes.
+    fn finalize(&self, _file: usize) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+use crate::content::ContentFile;
+use crate::resume::FileState;
+use crate::storage::Storage;
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// How many files are kept open unless told otherwise, well below the
+/// usual limit of 1024 descriptors per process.
+pub const DEFAULT_OPEN_FILE_LIMIT: usize = 64;
+
+/// Stores the torrent as regular files in the download folder.
+#[derive(Debug)]
+pub struct FileStorage {
+    files: Vec<ContentFile>,
+    //current location of every file, rename moves them
+    paths: RwLock<Vec<PathBuf>>,
+    handles: Mutex<Handles>,
+    //written to since the last flush
+    dirty: Mutex<HashSet<usize>>,
+}
+
+impl FileStorage {
+    pub fn new(files: &[ContentFile]) -> FileStorage {
+        FileStorage {
+            paths: RwLock::new(files.iter().map(|f| f.path.clone()).collect()),
+            files: files.to_vec(),
+            handles: Mutex::new(Handles {
+                open: VecDeque::new(),
+                limit: DEFAULT_OPEN_FILE_LIMIT,
+            }),
+            dirty: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn path(&self, file: usize) -> PathBuf {
+        self.paths.read().unwrap()[file].clone()
+    }
+
+    /// A handle to `file` from the cache, opened if needed. Reads and writes
+    /// go through positional calls, so the same handle can be used by
+    /// several threads at once.
+    fn handle(&self, file: usize, write: bool) -> io::Result<Arc<fs::File>> {
+        //paths before handles, the order rename takes them in
+        let paths = self.paths.read().unwrap();
+        let mut handles = self.handles.lock().unwrap();
+        if let Some(i) = handles.open.iter().position(|h| h.file == file) {
+            let handle = handles.open.remove(i).unwrap();
+            if handle.writable || !write {
+                let f = handle.f.clone();
+                handles.open.push_back(handle);
+                return Ok(f);
+            }
+        }
+        //files that are only read (seeding, hash check) are opened read only,
+        //they may not be writable at all
+        let f = Arc::new(
+            OpenOptions::new()
+                .read(true)
+                .write(write)
+                .open(&paths[file])?,
+        );
+        while handles.open.len() >= handles.limit {
+            handles.open.pop_front();
+        }
+        handles.open.push_back(Handle {
+            file,
+            writable: write,
+            f: f.clone(),
+        });
+        Ok(f)
+    }
+
+    fn close_handle(&self, file: usize) {
+        self.handles.lock().unwrap().open.retain(|h| h.file != file);
+    }
+}
+
+/// Open files, least recently used first. A handle that is evicted while
+/// another thread still uses it is closed once that thread is done.
+#[derive(Debug)]
+struct Handles {
+    open: VecDeque<Handle>,
+    limit: usize,
+}
+
+#[derive(Debug)]
+struct Handle {
+    file: usize,
+    writable: bool,
+    f: Arc<fs::File>,
+}
+
+impl Storage for FileStorage {
+    fn read(&self, file: usize, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        read_exact_at(&*self.handle(file, false)?, buf, offset)
+    }
+
+    fn write(&self, file: usize, offset: u64, data: &[u8]) -> io::Result<()> {
+        write_all_at(&*self.handle(file, true)?, data, offset)?;
+        self.dirty.lock().unwrap().insert(file);
+        Ok(())
+    }
+
+    fn preallocate(&self, file: usize, length: u64) -> io::Result<()> {
+        let path = self.path(file);
+        if let Some(dir_path) = path.parent() {
+            fs::create_dir_all(dir_path)?;
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(false);
+        //windows only takes attributes when the file is created
+        #[cfg(windows)]
+        if self.files[file].attributes.hidden {
+            use std::os::windows::fs::OpenOptionsExt;
+            const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+            options.attributes(FILE_ATTRIBUTE_HIDDEN);
+        }
+        let f = options.open(path)?;
+        //setting the same length again would still touch mtime and spoil resume data
+        if f.metadata()?.len() != length {
+            f.set_len(length)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let dirty = std::mem::take(&mut *self.dirty.lock().unwrap());
+        for file in dirty {
+            self.handle(file, true)?.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn close(&self) -> io::Result<()> {
+        let flushed = self.flush();
+        self.handles.lock().unwrap().open.clear();
+        flushed
+    }
+
+    fn set_open_file_limit(&self, limit: usize) {
+        let mut handles = self.handles.lock().unwrap();
+        handles.limit = limit.max(1);
+        while handles.open.len() > handles.limit {
+            handles.open.pop_front();
+        }
+    }
+
+    fn rename(&self, file: usize, to: &Path) -> io::Result<()> {
+        let mut paths = self.paths.write().unwrap();
+        if let Some(dir_path) = to.parent() {
+            fs::create_dir_all(dir_path)?;
+        }
+        //windows refuses to move a file that is open
+        self.close_handle(file);
+        fs::rename(&paths[file], to)?;
+        paths[file] = to.to_path_buf();
+        Ok(())
+    }
+
+    fn delete(&self, file: usize) -> io::Result<()> {
+        self.close_handle(file);
+        match fs::remove_file(self.path(file)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            r => r,
+        }
+    }
+
+    fn file_state(&self, file: usize) -> FileState {
+        FileState::read(&self.path(file))
+    }
+
+    /// Symlinks are only created now, so no piece write can ever go through one.
+    fn finalize(&self, file: usize) -> io::Result<()> {
+        let path = self.path(file);
+        if let Some(target) = &self.files[file].symlink {
+            return create_symlink(&path, target);
+        }
+        #[cfg(unix)]
+        if self.files[file].attributes.executable {
+            use std::os::unix::fs::PermissionsExt;
+            let mut permissions = fs::metadata(&path)?.permissions();
+            let mode = permissions.mode();
+            //executable for whoever can read it
+            permissions.set_mode(mode | ((mode & 0o444) >> 2));
+            fs::set_permissions(&path, permissions)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(f: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(f, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_all_at(f: &fs::File, data: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(f, data, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(f: &fs::File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match f.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_all_at(f: &fs::File, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        match f.seek_write(data, offset)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => {
+                data = &data[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Creates `link` pointing at `target`, replacing an older link or an empty
+/// placeholder, but never a file with data in it.
+fn create_symlink(link: &Path, target: &Path) -> io::Result<()> {
+    if let Ok(metadata) = fs::symlink_metadata(link) {
+        if metadata.file_type().is_symlink() || (metadata.is_file() && metadata.len() == 0) {
+            fs::remove_file(link)?;
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "a file is in the way",
+            ));
+        }
+    }
+    if let Some(dir_path) = link.parent() {
+        fs::create_dir_all(dir_path)?;
+    }
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(target, link);
+    #[cfg(windows)]
+    return std::os::windows::fs::symlink_file(target, link);
+    #[cfg(not(any(unix, windows)))]
+    return Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "symlinks are not supported",
+    ));
+}
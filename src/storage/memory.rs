@@ -0,0 +1,104 @@
+use crate::resume::FileState;
+use crate::storage::Storage;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Keeps every file in RAM, for tests and swarms that never touch a disk.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    files: Mutex<Vec<Option<MemoryFile>>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryFile {
+    data: Vec<u8>,
+    //stands in for mtime, bumped on every change
+    generation: i64,
+}
+
+impl MemoryStorage {
+    pub fn new(file_count: usize) -> MemoryStorage {
+        let mut files = vec![];
+        files.resize_with(file_count, || None);
+        MemoryStorage {
+            files: Mutex::new(files),
+        }
+    }
+
+    /// A copy of the file, `None` if it was never allocated.
+    pub fn contents(&self, file: usize) -> Option<Vec<u8>> {
+        self.files.lock().unwrap()[file]
+            .as_ref()
+            .map(|f| f.data.clone())
+    }
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "file was never allocated")
+}
+
+impl Storage for MemoryStorage {
+    fn read(&self, file: usize, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let files = self.files.lock().unwrap();
+        let f = files[file].as_ref().ok_or_else(not_found)?;
+        let start = offset as usize;
+        match f.data.get(start..start + buf.len()) {
+            Some(data) => {
+                buf.copy_from_slice(data);
+                Ok(())
+            }
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    fn write(&self, file: usize, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let f = files[file].as_mut().ok_or_else(not_found)?;
+        let start = offset as usize;
+        if f.data.len() < start + data.len() {
+            f.data.resize(start + data.len(), 0);
+        }
+        f.data[start..start + data.len()].copy_from_slice(data);
+        f.generation += 1;
+        Ok(())
+    }
+
+    fn preallocate(&self, file: usize, length: u64) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let f = files[file].get_or_insert_with(MemoryFile::default);
+        if f.data.len() as u64 != length {
+            f.data.resize(length as usize, 0);
+            f.generation += 1;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn rename(&self, file: usize, _to: &Path) -> io::Result<()> {
+        //files are known by index only, there is no name to change
+        self.files.lock().unwrap()[file]
+            .as_ref()
+            .map(|_| ())
+            .ok_or_else(not_found)
+    }
+
+    fn delete(&self, file: usize) -> io::Result<()> {
+        self.files.lock().unwrap()[file] = None;
+        Ok(())
+    }
+
+    fn file_state(&self, file: usize) -> FileState {
+        match &self.files.lock().unwrap()[file] {
+            Some(f) => FileState {
+                length: f.data.len() as u64,
+                mtime: f.generation,
+                mtime_nanos: 0,
+            },
+            None => FileState::default(),
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use crate::content::ContentFile;
+use crate::resume::FileState;
+use crate::storage::{FileStorage, Storage};
+use memmap2::{MmapMut, MmapOptions};
+use std::collections::{HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Size of one mapped region. Files are never mapped whole, a 200 GB
+/// torrent would otherwise eat the address space of a 32 bit build.
+const WINDOW_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Serves reads and writes from memory mapped windows of the files.
+/// Everything else (allocation, renames, attributes) is done by a
+/// `FileStorage` underneath.
+///
+/// Written data is only guaranteed to be on disk after `flush`, which is
+/// what resume data should be saved after.
+#[derive(Debug)]
+pub struct MmapStorage {
+    inner: FileStorage,
+    lengths: Vec<u64>,
+    windows: Mutex<Windows>,
+}
+
+#[derive(Debug)]
+struct Windows {
+    maps: HashMap<(usize, u64), MmapMut>,
+    //least recently used first
+    order: VecDeque<(usize, u64)>,
+    limit: usize,
+}
+
+impl MmapStorage {
+    /// Keeps at most `window_limit` windows of 16 MiB mapped at once.
+    pub fn new(files: &[ContentFile], window_limit: usize) -> MmapStorage {
+        MmapStorage {
+            inner: FileStorage::new(files),
+            lengths: files.iter().map(|f| f.length as u64).collect(),
+            windows: Mutex::new(Windows {
+                maps: HashMap::new(),
+                order: VecDeque::new(),
+                limit: window_limit.max(1),
+            }),
+        }
+    }
+
+    /// Runs `f` on every window that overlaps `offset..offset + len`,
+    /// with the part of the window and the matching part of the range.
+    fn with_windows(
+        &self,
+        file: usize,
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(&mut [u8], usize),
+    ) -> io::Result<()> {
+        if offset + len as u64 > self.lengths[file] {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut windows = self.windows.lock().unwrap();
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let window = position / WINDOW_SIZE;
+            let map = windows.get(self, file, window)?;
+            let start = (position - window * WINDOW_SIZE) as usize;
+            let how_much = std::cmp::min(map.len() - start, len - done);
+            f(&mut map[start..start + how_much], done);
+            done += how_much;
+        }
+        Ok(())
+    }
+
+    /// Unmaps every window of a file, after flushing it.
+    fn drop_windows(&self, file: usize) -> io::Result<()> {
+        let mut windows = self.windows.lock().unwrap();
+        let keys = windows
+            .order
+            .iter()
+            .filter(|(f, _)| *f == file)
+            .copied()
+            .collect::<Vec<(usize, u64)>>();
+        for key in keys {
+            windows.evict(key)?;
+        }
+        Ok(())
+    }
+}
+
+impl Windows {
+    fn get(&mut self, storage: &MmapStorage, file: usize, window: u64) -> io::Result<&mut MmapMut> {
+        let key = (file, window);
+        if self.maps.contains_key(&key) {
+            self.order.retain(|k| *k != key);
+            self.order.push_back(key);
+        } else {
+            if self.maps.len() >= self.limit {
+                if let Some(oldest) = self.order.front().copied() {
+                    self.evict(oldest)?;
+                }
+            }
+            let map = map_window(storage, file, window)?;
+            self.maps.insert(key, map);
+            self.order.push_back(key);
+        }
+        Ok(self.maps.get_mut(&key).unwrap())
+    }
+
+    fn evict(&mut self, key: (usize, u64)) -> io::Result<()> {
+        self.order.retain(|k| *k != key);
+        if let Some(map) = self.maps.remove(&key) {
+            map.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn map_window(storage: &MmapStorage, file: usize, window: u64) -> io::Result<MmapMut> {
+    let offset = window * WINDOW_SIZE;
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(storage.inner.path(file))?;
+    //touching a mapping past the end of the file is a SIGBUS, not an error,
+    //so never map more than the file actually has
+    let actual = f.metadata()?.len();
+    let len = std::cmp::min(WINDOW_SIZE, storage.lengths[file].saturating_sub(offset));
+    if len == 0 || offset + len > actual {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    // SAFETY: the file is ours for as long as the torrent runs and its length
+    // was checked above. Someone truncating it behind our back is the one
+    // case this can't guard against.
+    unsafe {
+        MmapOptions::new()
+            .offset(offset)
+            .len(len as usize)
+            .map_mut(&f)
+    }
+}
+
+impl Storage for MmapStorage {
+    fn read(&self, file: usize, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.with_windows(file, offset, buf.len(), |map, done| {
+            buf[done..done + map.len()].copy_from_slice(map)
+        })
+    }
+
+    fn write(&self, file: usize, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.with_windows(file, offset, data.len(), |map, done| {
+            let len = map.len();
+            map.copy_from_slice(&data[done..done + len])
+        })
+    }
+
+    fn preallocate(&self, file: usize, length: u64) -> io::Result<()> {
+        self.inner.preallocate(file, length)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let windows = self.windows.lock().unwrap();
+        for map in windows.maps.values() {
+            map.flush()?;
+        }
+        self.inner.flush()
+    }
+
+    fn close(&self) -> io::Result<()> {
+        let mut windows = self.windows.lock().unwrap();
+        let keys = windows.order.iter().copied().collect::<Vec<(usize, u64)>>();
+        for key in keys {
+            windows.evict(key)?;
+        }
+        drop(windows);
+        self.inner.close()
+    }
+
+    fn set_open_file_limit(&self, limit: usize) {
+        self.inner.set_open_file_limit(limit)
+    }
+
+    fn rename(&self, file: usize, to: &Path) -> io::Result<()> {
+        self.drop_windows(file)?;
+        self.inner.rename(file, to)
+    }
+
+    fn delete(&self, file: usize) -> io::Result<()> {
+        self.drop_windows(file)?;
+        self.inner.delete(file)
+    }
+
+    fn file_state(&self, file: usize) -> FileState {
+        self.inner.file_state(file)
+    }
+
+    fn finalize(&self, file: usize) -> io::Result<()> {
+        self.inner.finalize(file)
+    }
+}
@@ -1,9 +1,15 @@
 use bendy::decoding::{Error as DecodeError, FromBencode, Object, ResultExt};
 use bendy::encoding::{AsString, Error as EncodeError, SingleItemEncoder, ToBencode};
-use magnet_url::Magnet;
+use log::debug;
 use sha1::{Digest, Sha1};
+use std::collections::HashSet;
 use std::fmt;
 use std::fmt::Write;
+mod raw;
+use raw::{RawInfo, RawTorrentFile};
+#[cfg(test)]
+pub(crate) mod fixture;
+mod sanitize;
 
 #[derive(Debug, Clone)]
 pub struct TorrentFile {
@@ -17,6 +23,44 @@ pub struct TorrentFile {
     pub encoding: Option<String>,
 }
 
+impl TorrentFile {
+    /// Decodes a .torrent file and validates its info dictionary.
+    pub fn from_bytes(bytes: &[u8]) -> Result<TorrentFile, MetainfoError> {
+        let raw = RawTorrentFile::from_bencode(bytes).map_err(MetainfoError::Bencode)?;
+        TorrentFile::from_raw(raw)
+    }
+
+    fn from_raw(raw: RawTorrentFile) -> Result<TorrentFile, MetainfoError> {
+        let announce = raw
+            .announce
+            .ok_or(MetainfoError::MissingField("announce"))?;
+        let info_raw = raw.info.ok_or(MetainfoError::MissingField("info"))?;
+
+        let info_hash = InfoHash::new(&info_raw);
+        let info = RawInfo::from_bencode(&info_raw).map_err(MetainfoError::Bencode)?;
+        let info = Info::from_raw(info)?;
+        debug!(
+            target: "metainfo",
+            info_hash:% = info_hash.as_string(),
+            name:% = info.name,
+            files = info.files.len(),
+            pieces = info.piece_count;
+            "Parsed torrent file"
+        );
+
+        Ok(TorrentFile {
+            announce,
+            announce_list: raw.announce_list,
+            info,
+            info_hash,
+            creation_date: raw.creation_date,
+            comment: raw.comment,
+            created: raw.created,
+            encoding: raw.encoding,
+        })
+    }
+}
+
 // impl TorrentFile {
 //     fn from_magnet_link(magnet: Magnet) -> Self {
 
@@ -24,83 +68,9 @@ pub struct TorrentFile {
 // }
 
 impl FromBencode for TorrentFile {
-    //const EXPECTED_RECURSION_DEPTH: usize = 1;
-
     fn decode_bencode_object(object: Object) -> Result<Self, DecodeError> {
-        let mut announce = None;
-        let mut announce_list = None;
-        let mut info = None;
-        let mut info_hash = None;
-        let mut creation_date = None;
-        let mut comment = None;
-        let mut created = None;
-        let mut encoding = None;
-
-        let mut dict = object.try_into_dictionary()?;
-        while let Some(pair) = dict.next_pair()? {
-            match pair {
-                (b"announce", value) => {
-                    announce = String::decode_bencode_object(value)
-                        .context("announce")
-                        .map(Some)?;
-                }
-                (b"announce-list", value) => {
-                    announce_list = Vec::<Vec<String>>::decode_bencode_object(value)
-                        .context("announce-list")
-                        .map(Some)?;
-                }
-                (b"info", value) => {
-                    let i = value.try_into_dictionary().unwrap().into_raw().unwrap();
-
-                    info_hash = Some(InfoHash::new(i));
-                    info = Some(Info::from_bencode(i))
-                }
-                (b"creation_date", value) => {
-                    creation_date = u32::decode_bencode_object(value)
-                        .context("creation_date")
-                        .map(Some)?;
-                }
-                (b"comment", value) => {
-                    comment = String::decode_bencode_object(value)
-                        .context("comment")
-                        .map(Some)?;
-                }
-                (b"created", value) => {
-                    created = String::decode_bencode_object(value)
-                        .context("created")
-                        .map(Some)?;
-                }
-                (b"encoding", value) => {
-                    encoding = String::decode_bencode_object(value)
-                        .context("encoding")
-                        .map(Some)?;
-                }
-                (unknown_field, _) => {
-                    println!(
-                        "Not done in TorrentFile -{:?}",
-                        String::from_utf8_lossy(unknown_field)
-                    );
-                    // return Err(DecodeError::unexpected_field(String::from_utf8_lossy(
-                    //     unknown_field,
-                    // )));
-                }
-            }
-        }
-
-        let announce = announce.ok_or_else(|| DecodeError::missing_field("announce"))?;
-        let info = info.ok_or_else(|| DecodeError::missing_field("info"))??;
-        let info_hash = info_hash.ok_or_else(|| DecodeError::missing_field("info_hash"))?;
-
-        Ok(TorrentFile {
-            announce,
-            announce_list,
-            info,
-            info_hash,
-            creation_date,
-            comment,
-            created,
-            encoding,
-        })
+        let raw = RawTorrentFile::decode_bencode_object(object)?;
+        TorrentFile::from_raw(raw).map_err(DecodeError::malformed_content)
     }
 }
 
@@ -132,126 +102,122 @@ impl Info {
     }
 
     pub fn get_last_piece_size(&self) -> u32 {
-        self.length as u32 - (self.piece_length * (self.piece_count - 1))
+        (self.length - self.piece_length as usize * (self.piece_count as usize - 1)) as u32
     }
 }
 
-impl FromBencode for Info {
-    //const EXPECTED_RECURSION_DEPTH: usize = 1;
-
-    fn decode_bencode_object(object: Object) -> Result<Self, DecodeError> {
-        // let mut file_duration = None;
-        // let mut file_media = None;
-        let mut len = None;
-        let mut name = None;
-        let mut piece_length = None;
-        let mut pieces = None;
-        let mut files = vec![];
-        // let mut profiles = None;
-
-        let mut dict = object.try_into_dictionary()?;
-        while let Some(pair) = dict.next_pair()? {
-            match pair {
-                // "profiles"
-                // (b"file-duration", value) => {
-                //     file_duration = Vec::<usize>::decode_bencode_object(value)
-                //         .context("file-duration")
-                //         .map(Some)?;
-                // }
-                // (b"file-media", value) => {
-                //     file_media = Vec::<usize>::decode_bencode_object(value)
-                //         .context("file-media")
-                //         .map(Some)?;
-                // }
-                (b"length", value) => {
-                    len = usize::decode_bencode_object(value)
-                        .context("length")
-                        .map(Some)?;
-                }
-                (b"name", value) => {
-                    name = String::decode_bencode_object(value)
-                        .context("name")
-                        .map(Some)?;
-                }
-                (b"piece length", value) => {
-                    piece_length = u32::decode_bencode_object(value)
-                        .context("piece length")
-                        .map(Some)?;
-                }
-                (b"pieces", value) => {
-                    //pieces is not human readable, so we can't put it to String
-                    let value = value.try_into_bytes().unwrap().to_vec();
-                    if value.len() % 20 == 0 {
-                        pieces = Some(value);
-                    }
-                }
-                (b"files", value) => {
-                    let mut value = value.try_into_list().unwrap();
-                    while let Some(d) = value.next_object()? {
-                        let d = d.try_into_dictionary().unwrap().into_raw().unwrap();
+impl Info {
+    fn from_raw(raw: RawInfo) -> Result<Info, MetainfoError> {
+        let name = raw.name.ok_or(MetainfoError::MissingField("name"))?;
+        let name = sanitize::decode(&name, raw.name_utf8.as_deref());
+        if name.is_empty() {
+            return Err(MetainfoError::EmptyName);
+        }
+        let name = sanitize::sanitize_component(&name);
+
+        let piece_length = raw
+            .piece_length
+            .ok_or(MetainfoError::MissingField("piece length"))?;
+        let piece_length = match u32::try_from(piece_length) {
+            Ok(l) if l.is_power_of_two() => l,
+            _ => return Err(MetainfoError::InvalidPieceLength(piece_length)),
+        };
+
+        let pieces = raw.pieces.ok_or(MetainfoError::MissingField("pieces"))?;
+        if pieces.len() % 20 != 0 {
+            return Err(MetainfoError::InvalidPiecesLength(pieces.len()));
+        }
 
-                        let file = File::from_bencode(d)?;
-                        files.push(file);
+        let files = match raw.files {
+            Some(raw_files) => {
+                let mut files = Vec::with_capacity(raw_files.len());
+                let mut seen = HashSet::new();
+                for (index, f) in raw_files.into_iter().enumerate() {
+                    let length = f.length.ok_or(MetainfoError::MissingField("length"))?;
+                    let path = f.path.ok_or(MetainfoError::MissingField("path"))?;
+                    let path = sanitize::sanitize_path(&path, f.path_utf8.as_deref())
+                        .ok_or(MetainfoError::EmptyPath(index))?;
+                    let attributes = FileAttributes::parse(f.attr.as_deref());
+                    //pad files are commonly named after their size, so repeats are fine
+                    if !attributes.pad && !seen.insert(path.clone()) {
+                        return Err(MetainfoError::DuplicatePath(path));
                     }
+                    let symlink_path = match (attributes.symlink, f.symlink_path) {
+                        (false, _) => None,
+                        (true, Some(target)) => Some(
+                            sanitize::sanitize_path(&target, None)
+                                .ok_or(MetainfoError::EmptyPath(index))?,
+                        ),
+                        (true, None) => return Err(MetainfoError::MissingField("symlink path")),
+                    };
+                    files.push(File {
+                        length: to_length(index, length)?,
+                        path,
+                        attributes,
+                        symlink_path,
+                        sha1: f.sha1.and_then(|h| h.try_into().ok()),
+                    });
                 }
-                // (b"profiles", value) => {
-                //     profiles = Vec::<Profile>::decode_bencode_object(value)
-                //         .context("profiles")
-                //         .map(Some)?;
-                // }
-                (unknown_field, _) => {
-                    println!(
-                        "Not done in Info - {:?}",
-                        String::from_utf8_lossy(unknown_field)
-                    );
-                }
+                files
             }
-        }
-
-        //let file_duration =
-        //    file_duration.ok_or_else(|| DecodeError::missing_field("file_duration"))?;
-        //let file_media = file_media.ok_or_else(|| DecodeError::missing_field("file_media"))?;
-        let name = name.ok_or_else(|| DecodeError::missing_field("name"))?;
-        let piece_length =
-            piece_length.ok_or_else(|| DecodeError::missing_field("piece_length"))?;
-        let pieces = pieces.ok_or_else(|| DecodeError::missing_field("pieces"))?;
-        let mut length = 0;
-        if len == None && !files.is_empty() {
-            for f in &files {
-                length += f.length
+            None => {
+                let length = raw.length.ok_or(MetainfoError::MissingField("length"))?;
+                let mut attributes = FileAttributes::parse(raw.attr.as_deref());
+                //a single file torrent can't point elsewhere or consist of padding
+                attributes.pad = false;
+                attributes.symlink = false;
+                vec![File {
+                    length: to_length(0, length)?,
+                    path: name.clone(),
+                    attributes,
+                    symlink_path: None,
+                    sha1: raw.sha1.and_then(|h| h.try_into().ok()),
+                }]
             }
-        } else {
-            length = len.ok_or_else(|| DecodeError::missing_field("length"))?;
+        };
+
+        let length = files
+            .iter()
+            .try_fold(0usize, |total, f| total.checked_add(f.length))
+            .ok_or(MetainfoError::LengthOverflow)?;
+        if length == 0 {
+            return Err(MetainfoError::NoContent);
         }
-        if files.is_empty() {
-            files.push(File {
-                length,
-                path: name.clone(),
+
+        let piece_count = u32::try_from(length.div_ceil(piece_length as usize))
+            .map_err(|_| MetainfoError::LengthOverflow)?;
+        if pieces.len() / 20 != piece_count as usize {
+            return Err(MetainfoError::PieceCountMismatch {
+                expected: piece_count,
+                actual: pieces.len() / 20,
             });
         }
-        //let profiles = profiles.ok_or_else(|| DecodeError::missing_field("profiles"))?;
-
-        let piece_count = (length / piece_length as usize
-            + if length % piece_length as usize == 0 {
-                0
-            } else {
-                1
-            }) as u32;
 
         Ok(Info {
-            //file_duration,
-            //file_media,
             length,
             name,
             piece_length,
             pieces,
             files,
-            //profiles,
             piece_count,
         })
     }
 }
 
+fn to_length(file: usize, length: i64) -> Result<usize, MetainfoError> {
+    if length < 0 {
+        return Err(MetainfoError::NegativeLength { file, length });
+    }
+    usize::try_from(length).map_err(|_| MetainfoError::LengthOverflow)
+}
+
+impl FromBencode for Info {
+    fn decode_bencode_object(object: Object) -> Result<Self, DecodeError> {
+        let raw = RawInfo::decode_bencode_object(object)?;
+        Info::from_raw(raw).map_err(DecodeError::malformed_content)
+    }
+}
+
 impl ToBencode for Info {
     const MAX_DEPTH: usize = 3;
 
@@ -259,9 +225,9 @@ impl ToBencode for Info {
         encoder.emit_dict(|mut e| {
             //e.emit_pair(b"file-duration", &self.file_duration)?;
             //e.emit_pair(b"file-media", &self.file_media)?;
-            e.emit_pair(b"length", &self.length)?;
+            e.emit_pair(b"length", self.length)?;
             e.emit_pair(b"name", &self.name)?;
-            e.emit_pair(b"piece length", &self.piece_length)?;
+            e.emit_pair(b"piece length", self.piece_length)?;
             //Clone is expensive? TODO rewrite?
             let pieces = ByteStringWrapper(self.pieces.clone());
             e.emit_pair(b"pieces", pieces)?;
@@ -295,9 +261,7 @@ impl InfoHash {
         let mut hasher = Sha1::new();
         hasher.update(bencode);
         let hexes = hasher.finalize();
-        InfoHash {
-            hash: hexes.try_into().expect("Wrong length"),
-        }
+        InfoHash { hash: hexes.into() }
     }
 
     pub fn raw(&self) -> &[u8; 20] {
@@ -324,46 +288,98 @@ impl InfoHash {
 #[derive(Debug, Clone)]
 pub struct File {
     pub length: usize,
+    /// Sanitized path components joined with "/".
+    /// Components never contain a separator themselves.
     pub path: String,
+    pub attributes: FileAttributes,
+    /// Symlink target relative to the torrent root, in the same form as `path`.
+    pub symlink_path: Option<String>,
+    pub sha1: Option<[u8; 20]>,
 }
 
-impl FromBencode for File {
-    //const EXPECTED_RECURSION_DEPTH: usize = 1;
+impl File {
+    pub fn components(&self) -> impl Iterator<Item = &str> {
+        self.path.split('/')
+    }
+}
 
-    fn decode_bencode_object(object: Object) -> Result<Self, DecodeError> {
-        let mut length = None;
-        let mut path = None;
+/// BEP 47 file attributes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+    /// Padding to align the next file to a piece boundary, all zeros, never stored.
+    pub pad: bool,
+    pub executable: bool,
+    pub hidden: bool,
+    pub symlink: bool,
+}
 
-        let mut dict = object.try_into_dictionary()?;
-        while let Some(pair) = dict.next_pair()? {
-            match pair {
-                (b"length", value) => {
-                    length = usize::decode_bencode_object(value)
-                        .context("length")
-                        .map(Some)?;
-                }
-                (b"path", value) => {
-                    let p = Vec::<String>::decode_bencode_object(value).context("path")?;
-                    let pb = p.join("/");
-                    path = Some(pb);
-                }
-                (unknown_field, _) => {
-                    println!(
-                        "Not done in File -{:?}",
-                        String::from_utf8_lossy(unknown_field)
-                    );
-                }
-            }
+impl FileAttributes {
+    fn parse(attr: Option<&[u8]>) -> FileAttributes {
+        let attr = attr.unwrap_or_default();
+        FileAttributes {
+            pad: attr.contains(&b'p'),
+            executable: attr.contains(&b'x'),
+            hidden: attr.contains(&b'h'),
+            symlink: attr.contains(&b'l'),
         }
+    }
+}
 
-        let length = length.ok_or_else(|| DecodeError::missing_field("length"))?;
-        let path = path.ok_or_else(|| DecodeError::missing_field("path"))?;
+/// Reasons a .torrent file is rejected by `TorrentFile::from_bytes`.
+#[derive(Debug, Clone)]
+pub enum MetainfoError {
+    Bencode(DecodeError),
+    MissingField(&'static str),
+    EmptyName,
+    EmptyPath(usize),
+    DuplicatePath(String),
+    NoContent,
+    InvalidPieceLength(i64),
+    InvalidPiecesLength(usize),
+    PieceCountMismatch { expected: u32, actual: usize },
+    NegativeLength { file: usize, length: i64 },
+    LengthOverflow,
+}
+
+impl fmt::Display for MetainfoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MetainfoError::Bencode(e) => write!(f, "malformed bencode: {}", e),
+            MetainfoError::MissingField(field) => write!(f, "missing field: {}", field),
+            MetainfoError::EmptyName => write!(f, "torrent name is empty"),
+            MetainfoError::EmptyPath(file) => write!(f, "file {} has an empty path", file),
+            MetainfoError::DuplicatePath(path) => write!(f, "duplicate file path: {}", path),
+            MetainfoError::NoContent => write!(f, "torrent has no content"),
+            MetainfoError::InvalidPieceLength(l) => {
+                write!(f, "piece length {} is not a positive power of two", l)
+            }
+            MetainfoError::InvalidPiecesLength(l) => {
+                write!(f, "pieces length {} is not a multiple of 20", l)
+            }
+            MetainfoError::PieceCountMismatch { expected, actual } => write!(
+                f,
+                "total length needs {} pieces, but {} hashes are given",
+                expected, actual
+            ),
+            MetainfoError::NegativeLength { file, length } => {
+                write!(f, "file {} has negative length {}", file, length)
+            }
+            MetainfoError::LengthOverflow => write!(f, "torrent length overflows"),
+        }
+    }
+}
 
-        Ok(File { length, path })
+impl std::error::Error for MetainfoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MetainfoError::Bencode(e) => Some(e),
+            _ => None,
+        }
     }
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 struct Profile {
     acodec: String,
     height: usize,
@@ -401,12 +417,7 @@ impl FromBencode for Profile {
                         .context("width")
                         .map(Some)?;
                 }
-                (unknown_field, _) => {
-                    println!(
-                        "Not done in Profile - {:?}",
-                        String::from_utf8_lossy(unknown_field)
-                    );
-                }
+                (_, _) => (),
             }
         }
 
@@ -430,9 +441,9 @@ impl ToBencode for Profile {
     fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncodeError> {
         encoder.emit_dict(|mut e| {
             e.emit_pair(b"acodec", &self.acodec)?;
-            e.emit_pair(b"height", &self.height)?;
+            e.emit_pair(b"height", self.height)?;
             e.emit_pair(b"vcodec", &self.vcodec)?;
-            e.emit_pair(b"width", &self.width)?;
+            e.emit_pair(b"width", self.width)?;
 
             Ok(())
         })
@@ -449,3 +460,140 @@ impl ToBencode for ByteStringWrapper {
         encoder.emit(&content)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::TestTorrent;
+    use super::*;
+
+    fn torrent(length: i64, piece_length: i64, hashes: usize) -> TestTorrent {
+        TestTorrent {
+            length,
+            piece_length,
+            pieces: vec![0; hashes * 20],
+            ..TestTorrent::new(&[], 16384)
+        }
+    }
+
+    #[test]
+    fn piece_count_matches_length() {
+        let tf = torrent(16385, 16384, 2).parse().unwrap();
+        assert_eq!(tf.info.piece_count, 2);
+        assert!(matches!(
+            torrent(16385, 16384, 1).parse(),
+            Err(MetainfoError::PieceCountMismatch {
+                expected: 2,
+                actual: 1
+            })
+        ));
+        assert!(matches!(
+            torrent(16384, 16384, 2).parse(),
+            Err(MetainfoError::PieceCountMismatch {
+                expected: 1,
+                actual: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn hashes_are_20_bytes() {
+        for length in [19, 21] {
+            let mut t = torrent(16384, 16384, 1);
+            t.pieces.resize(length, 0);
+            assert!(matches!(
+                t.parse(),
+                Err(MetainfoError::InvalidPiecesLength(l)) if l == length
+            ));
+        }
+    }
+
+    #[test]
+    fn piece_length_is_a_power_of_two() {
+        for bad in [0, -16384, 3, 16383, 24576, 1 << 32] {
+            assert!(
+                matches!(
+                    torrent(16384, bad, 1).parse(),
+                    Err(MetainfoError::InvalidPieceLength(l)) if l == bad
+                ),
+                "{}",
+                bad
+            );
+        }
+        assert!(torrent(16384, 1, 16384).parse().is_ok());
+    }
+
+    #[test]
+    fn empty_content() {
+        assert!(matches!(
+            torrent(0, 16384, 0).parse(),
+            Err(MetainfoError::NoContent)
+        ));
+        assert!(matches!(
+            torrent(-1, 16384, 0).parse(),
+            Err(MetainfoError::NegativeLength {
+                file: 0,
+                length: -1
+            })
+        ));
+        let t = torrent(0, 16384, 1).with_files(&[(1, "a"), (-5, "b")]);
+        assert!(matches!(
+            t.parse(),
+            Err(MetainfoError::NegativeLength {
+                file: 1,
+                length: -5
+            })
+        ));
+    }
+
+    #[test]
+    fn total_length_overflows() {
+        let t =
+            torrent(0, 16384, 1).with_files(&[(i64::MAX, "a"), (i64::MAX, "b"), (i64::MAX, "c")]);
+        assert!(matches!(t.parse(), Err(MetainfoError::LengthOverflow)));
+    }
+
+    #[test]
+    fn duplicate_paths() {
+        let t = torrent(0, 16384, 1).with_files(&[(1, "d/a"), (1, "d/b"), (1, "d/a")]);
+        assert!(matches!(
+            t.parse(),
+            Err(MetainfoError::DuplicatePath(path)) if path == "d/a"
+        ));
+        //different in the torrent, the same once sanitized
+        let t = torrent(0, 16384, 1).with_files(&[(1, "a:b"), (1, "a_b")]);
+        assert!(matches!(
+            t.parse(),
+            Err(MetainfoError::DuplicatePath(path)) if path == "a_b"
+        ));
+        let t = torrent(0, 16384, 1).with_files(&[(1, "a"), (1, "b")]);
+        assert_eq!(t.parse().unwrap().info.files.len(), 2);
+    }
+
+    #[test]
+    fn empty_names() {
+        let mut t = torrent(16384, 16384, 1);
+        t.name = String::new();
+        assert!(matches!(t.parse(), Err(MetainfoError::EmptyName)));
+        let t = torrent(0, 16384, 1).with_files(&[(1, "a"), (1, "b//c")]);
+        assert!(matches!(t.parse(), Err(MetainfoError::EmptyPath(1))));
+    }
+
+    #[test]
+    fn malformed_bencode() {
+        let bytes = torrent(16384, 16384, 1).to_bytes();
+        for broken in [&bytes[..bytes.len() - 1], b"d8:announce", b"i1e", b"", b"l"] {
+            assert!(
+                matches!(
+                    TorrentFile::from_bytes(broken),
+                    Err(MetainfoError::Bencode(_))
+                ),
+                "{:?}",
+                String::from_utf8_lossy(broken)
+            );
+        }
+        assert!(matches!(
+            TorrentFile::from_bytes(b"d8:announce10:http://x/ae"),
+            Err(MetainfoError::MissingField("info"))
+        ));
+    }
+}
@@ -0,0 +1,82 @@
+//! Torrent files for tests, encoded with bendy instead of by hand.
+use super::{MetainfoError, TorrentFile};
+use bendy::encoding::{AsString, Error as EncodeError, SingleItemEncoder, ToBencode};
+use sha1::{Digest, Sha1};
+
+/// The fields are written as they are, invalid ones included.
+#[derive(Debug, Clone)]
+pub(crate) struct TestTorrent {
+    pub name: String,
+    pub piece_length: i64,
+    pub pieces: Vec<u8>,
+    /// Of the single file, unused once there are `files`.
+    pub length: i64,
+    /// (length, path components), makes it a multi file torrent.
+    pub files: Vec<(i64, Vec<String>)>,
+}
+
+impl TestTorrent {
+    /// A single file torrent named a.bin with the hashes of `data`.
+    pub fn new(data: &[u8], piece_length: usize) -> TestTorrent {
+        TestTorrent {
+            name: String::from("a.bin"),
+            piece_length: piece_length as i64,
+            pieces: data
+                .chunks(piece_length)
+                .flat_map(|piece| Sha1::digest(piece).to_vec())
+                .collect(),
+            length: data.len() as i64,
+            files: vec![],
+        }
+    }
+
+    /// Sets the files to `(length, "a/b")` pairs, the length of the single
+    /// file and the hashes are left alone.
+    pub fn with_files(mut self, files: &[(i64, &str)]) -> TestTorrent {
+        self.files = files
+            .iter()
+            .map(|(length, path)| (*length, path.split('/').map(String::from).collect()))
+            .collect();
+        self
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_bencode().unwrap()
+    }
+
+    pub fn parse(&self) -> Result<TorrentFile, MetainfoError> {
+        TorrentFile::from_bytes(&self.to_bytes())
+    }
+}
+
+impl ToBencode for TestTorrent {
+    const MAX_DEPTH: usize = 5;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), EncodeError> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"announce", "http://x/a")?;
+            e.emit_pair_with(b"info", |e| {
+                e.emit_dict(|mut e| {
+                    if self.files.is_empty() {
+                        e.emit_pair(b"length", self.length)?;
+                    } else {
+                        e.emit_pair_with(b"files", |e| {
+                            e.emit_list(|e| {
+                                for (length, path) in &self.files {
+                                    e.emit_dict(|mut e| {
+                                        e.emit_pair(b"length", length)?;
+                                        e.emit_pair(b"path", path)
+                                    })?;
+                                }
+                                Ok(())
+                            })
+                        })?;
+                    }
+                    e.emit_pair(b"name", &self.name)?;
+                    e.emit_pair(b"piece length", self.piece_length)?;
+                    e.emit_pair(b"pieces", AsString(&self.pieces))
+                })
+            })
+        })
+    }
+}
@@ -0,0 +1,212 @@
+//! Lenient first pass over the metainfo dictionaries.
+//!
+//! Everything here is decoded as close to the wire as possible (lengths stay
+//! `i64`, paths stay lists of components) so that `TorrentFile::from_raw` can
+//! tell a negative length apart from a missing one and report a proper
+//! `MetainfoError` instead of a generic bencode error.
+use bendy::decoding::{Error as DecodeError, FromBencode, Object, ResultExt};
+use bendy::encoding::AsString;
+use log::trace;
+
+#[derive(Debug, Default)]
+pub(crate) struct RawTorrentFile {
+    pub announce: Option<String>,
+    pub announce_list: Option<Vec<Vec<String>>>,
+    pub info: Option<Vec<u8>>,
+    pub creation_date: Option<u32>,
+    pub comment: Option<String>,
+    pub created: Option<String>,
+    pub encoding: Option<String>,
+}
+
+impl FromBencode for RawTorrentFile {
+    fn decode_bencode_object(object: Object) -> Result<Self, DecodeError> {
+        let mut raw = RawTorrentFile::default();
+
+        let mut dict = object.try_into_dictionary()?;
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"announce", value) => {
+                    raw.announce = String::decode_bencode_object(value)
+                        .context("announce")
+                        .map(Some)?;
+                }
+                (b"announce-list", value) => {
+                    raw.announce_list = Vec::<Vec<String>>::decode_bencode_object(value)
+                        .context("announce-list")
+                        .map(Some)?;
+                }
+                (b"info", value) => {
+                    //info hash is calculated over the exact bytes, so keep them as is
+                    let info = value.try_into_dictionary().context("info")?;
+                    raw.info = Some(info.into_raw().context("info")?.to_vec());
+                }
+                (b"creation_date", value) => {
+                    raw.creation_date = u32::decode_bencode_object(value)
+                        .context("creation_date")
+                        .map(Some)?;
+                }
+                (b"comment", value) => {
+                    raw.comment = String::decode_bencode_object(value)
+                        .context("comment")
+                        .map(Some)?;
+                }
+                (b"created", value) => {
+                    raw.created = String::decode_bencode_object(value)
+                        .context("created")
+                        .map(Some)?;
+                }
+                (b"encoding", value) => {
+                    raw.encoding = String::decode_bencode_object(value)
+                        .context("encoding")
+                        .map(Some)?;
+                }
+                //unknown keys are allowed by the spec
+                (key, _) => trace!(
+                    target: "metainfo",
+                    key:% = String::from_utf8_lossy(key);
+                    "Ignoring key in torrent file"
+                ),
+            }
+        }
+
+        Ok(raw)
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct RawInfo {
+    pub length: Option<i64>,
+    pub name: Option<Vec<u8>>,
+    pub name_utf8: Option<Vec<u8>>,
+    pub piece_length: Option<i64>,
+    pub pieces: Option<Vec<u8>>,
+    pub files: Option<Vec<RawFile>>,
+    pub attr: Option<Vec<u8>>,
+    pub sha1: Option<Vec<u8>>,
+}
+
+impl FromBencode for RawInfo {
+    fn decode_bencode_object(object: Object) -> Result<Self, DecodeError> {
+        let mut raw = RawInfo::default();
+
+        let mut dict = object.try_into_dictionary()?;
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"length", value) => {
+                    raw.length = i64::decode_bencode_object(value)
+                        .context("length")
+                        .map(Some)?;
+                }
+                (b"name", value) => {
+                    raw.name = AsString::decode_bencode_object(value)
+                        .context("name")
+                        .map(|n| Some(n.0))?;
+                }
+                (b"name.utf-8", value) => {
+                    raw.name_utf8 = AsString::decode_bencode_object(value)
+                        .context("name.utf-8")
+                        .map(|n| Some(n.0))?;
+                }
+                (b"piece length", value) => {
+                    raw.piece_length = i64::decode_bencode_object(value)
+                        .context("piece length")
+                        .map(Some)?;
+                }
+                (b"pieces", value) => {
+                    //pieces is not human readable, so we can't put it to String
+                    raw.pieces = Some(value.try_into_bytes().context("pieces")?.to_vec());
+                }
+                (b"files", value) => {
+                    let mut files = vec![];
+                    let mut list = value.try_into_list().context("files")?;
+                    while let Some(file) = list.next_object()? {
+                        files.push(RawFile::decode_bencode_object(file).context("files")?);
+                    }
+                    raw.files = Some(files);
+                }
+                (b"attr", value) => {
+                    raw.attr = AsString::decode_bencode_object(value)
+                        .context("attr")
+                        .map(|a| Some(a.0))?;
+                }
+                (b"sha1", value) => {
+                    raw.sha1 = AsString::decode_bencode_object(value)
+                        .context("sha1")
+                        .map(|h| Some(h.0))?;
+                }
+                (key, _) => trace!(
+                    target: "metainfo",
+                    key:% = String::from_utf8_lossy(key);
+                    "Ignoring key in info"
+                ),
+            }
+        }
+
+        Ok(raw)
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct RawFile {
+    pub length: Option<i64>,
+    pub path: Option<Vec<Vec<u8>>>,
+    pub path_utf8: Option<Vec<Vec<u8>>>,
+    pub attr: Option<Vec<u8>>,
+    pub symlink_path: Option<Vec<Vec<u8>>>,
+    pub sha1: Option<Vec<u8>>,
+}
+
+impl FromBencode for RawFile {
+    fn decode_bencode_object(object: Object) -> Result<Self, DecodeError> {
+        let mut raw = RawFile::default();
+
+        let mut dict = object.try_into_dictionary()?;
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"length", value) => {
+                    raw.length = i64::decode_bencode_object(value)
+                        .context("length")
+                        .map(Some)?;
+                }
+                (b"path", value) => {
+                    raw.path = decode_path(value).context("path").map(Some)?;
+                }
+                (b"path.utf-8", value) => {
+                    raw.path_utf8 = decode_path(value).context("path.utf-8").map(Some)?;
+                }
+                (b"attr", value) => {
+                    raw.attr = AsString::decode_bencode_object(value)
+                        .context("attr")
+                        .map(|a| Some(a.0))?;
+                }
+                (b"symlink path", value) => {
+                    raw.symlink_path = decode_path(value).context("symlink path").map(Some)?;
+                }
+                (b"sha1", value) => {
+                    raw.sha1 = AsString::decode_bencode_object(value)
+                        .context("sha1")
+                        .map(|h| Some(h.0))?;
+                }
+                (key, _) => trace!(
+                    target: "metainfo",
+                    key:% = String::from_utf8_lossy(key);
+                    "Ignoring key in file"
+                ),
+            }
+        }
+
+        Ok(raw)
+    }
+}
+
+/// Path components are kept as bytes, non UTF-8 names are common in old
+/// torrents and are dealt with when sanitizing.
+fn decode_path(object: Object) -> Result<Vec<Vec<u8>>, DecodeError> {
+    let mut components = vec![];
+    let mut list = object.try_into_list()?;
+    while let Some(component) = list.next_object()? {
+        components.push(component.try_into_bytes()?.to_vec());
+    }
+    Ok(components)
+}
@@ -0,0 +1,172 @@
+//! Turns path components from the metainfo into names that are safe to join
+//! under the download folder.
+//!
+//! The mapping is a pure function of the input bytes and does not depend on
+//! the platform we run on, so the same torrent always lands on the same paths
+//! and resume data stays valid.
+
+/// Longest component we are willing to create, in bytes.
+/// Most filesystems cap a single name at 255 bytes.
+const MAX_COMPONENT_LENGTH: usize = 255;
+
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Picks `name.utf-8`/`path.utf-8` over the plain field when it is present and
+/// valid, otherwise decodes the plain field lossily.
+pub(crate) fn decode(raw: &[u8], utf8: Option<&[u8]>) -> String {
+    if let Some(Ok(s)) = utf8.map(std::str::from_utf8) {
+        return s.to_string();
+    }
+    String::from_utf8_lossy(raw).into_owned()
+}
+
+/// Decodes and sanitizes a list of path components and joins them with "/".
+/// `utf8` is only used if it has the same number of components as `raw`.
+/// Returns `None` for an empty path or an empty component.
+pub(crate) fn sanitize_path(raw: &[Vec<u8>], utf8: Option<&[Vec<u8>]>) -> Option<String> {
+    let utf8 = utf8.filter(|p| p.len() == raw.len());
+    let components = raw
+        .iter()
+        .enumerate()
+        .map(|(i, c)| decode(c, utf8.map(|p| p[i].as_slice())))
+        .collect::<Vec<String>>();
+    if components.is_empty() || components.iter().any(|c| c.is_empty()) {
+        return None;
+    }
+    Some(
+        components
+            .iter()
+            .map(|c| sanitize_component(c))
+            .collect::<Vec<String>>()
+            .join("/"),
+    )
+}
+
+pub(crate) fn sanitize_component(component: &str) -> String {
+    if component == "." || component == ".." {
+        return String::from("_");
+    }
+
+    let mut name: String = component
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    //windows silently drops trailing dots and spaces, which could make two
+    //different names collide or a name vanish
+    let trimmed = name.trim_end_matches(['.', ' ']).len();
+    name.truncate(trimmed);
+    if name.is_empty() {
+        return String::from("_");
+    }
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        name.insert(0, '_');
+    }
+
+    truncate_component(name)
+}
+
+/// Cuts a component down to `MAX_COMPONENT_LENGTH` bytes, keeping a short
+/// extension so the file still opens with the right program.
+fn truncate_component(name: String) -> String {
+    if name.len() <= MAX_COMPONENT_LENGTH {
+        return name;
+    }
+
+    let extension = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= 16 => &name[dot..],
+        _ => "",
+    };
+    let mut end = MAX_COMPONENT_LENGTH - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(components: &[&str]) -> Option<String> {
+        let raw = components
+            .iter()
+            .map(|c| c.as_bytes().to_vec())
+            .collect::<Vec<Vec<u8>>>();
+        sanitize_path(&raw, None)
+    }
+
+    #[test]
+    fn traversal_stays_inside() {
+        assert_eq!(
+            path(&["..", "..", "etc", "passwd"]).unwrap(),
+            "_/_/etc/passwd"
+        );
+        assert_eq!(path(&[".", "a"]).unwrap(), "_/a");
+        assert_eq!(sanitize_component("../x"), ".._x");
+        assert_eq!(sanitize_component("..\\x"), ".._x");
+    }
+
+    #[test]
+    fn absolute_paths_become_relative() {
+        assert_eq!(path(&["/etc", "passwd"]).unwrap(), "_etc/passwd");
+        assert_eq!(sanitize_component("C:\\Windows"), "C__Windows");
+        assert_eq!(sanitize_component("\\\\server\\share"), "__server_share");
+    }
+
+    #[test]
+    fn reserved_names() {
+        assert_eq!(sanitize_component("CON"), "_CON");
+        assert_eq!(sanitize_component("nul.txt"), "_nul.txt");
+        assert_eq!(sanitize_component("Com1.tar.gz"), "_Com1.tar.gz");
+        assert_eq!(sanitize_component("LPT9 "), "_LPT9");
+        assert_eq!(sanitize_component("CONSOLE"), "CONSOLE");
+        assert_eq!(sanitize_component("COM0"), "COM0");
+    }
+
+    #[test]
+    fn trailing_dots_and_spaces() {
+        assert_eq!(sanitize_component("a. . "), "a");
+        assert_eq!(sanitize_component("..."), "_");
+        assert_eq!(sanitize_component(" "), "_");
+    }
+
+    #[test]
+    fn empty_components() {
+        assert_eq!(path(&[]), None);
+        assert_eq!(path(&["a", "", "b"]), None);
+        assert_eq!(path(&[""]), None);
+    }
+
+    #[test]
+    fn utf8_path_needs_every_component() {
+        let raw = vec![b"a".to_vec(), b"\xff".to_vec()];
+        let utf8 = vec![b"x".to_vec(), "é".as_bytes().to_vec()];
+        assert_eq!(sanitize_path(&raw, Some(&utf8)).unwrap(), "x/é");
+        assert_eq!(sanitize_path(&raw, Some(&utf8[..1])).unwrap(), "a/\u{fffd}");
+    }
+
+    #[test]
+    fn long_components_keep_their_extension() {
+        let name = format!("{}.mkv", "a".repeat(300));
+        let sanitized = sanitize_component(&name);
+        assert_eq!(sanitized.len(), MAX_COMPONENT_LENGTH);
+        assert!(sanitized.ends_with(".mkv"));
+        let name = "é".repeat(200);
+        let sanitized = sanitize_component(&name);
+        assert!(sanitized.len() <= MAX_COMPONENT_LENGTH);
+        assert!(name.starts_with(&sanitized));
+    }
+}
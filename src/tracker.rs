@@ -1,9 +1,25 @@
-use crate::{Content, PieceStatus};
 use crate::TorrentFile;
+use crate::{Content, PieceStatus, StatsSnapshot};
 use bendy::decoding::{Error as DecodeError, FromBencode, Object, ResultExt};
+use log::{debug, trace};
+use std::fmt;
+use std::io;
 use std::net::SocketAddr;
+use std::time::Duration;
 mod http;
 
+/// How long a tracker gets to answer an announce.
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Why a tracker gave us no peers.
+#[derive(Debug)]
+pub enum TrackerError {
+    /// The tracker couldn't be reached or the connection broke.
+    Connection { url: String, source: io::Error },
+    /// It answered with something that isn't a tracker response.
+    InvalidResponse { url: String, reason: String },
+}
+
 #[derive(Debug)]
 pub struct TrackerResponse {
     #[allow(dead_code)]
@@ -33,22 +49,25 @@ impl FromBencode for TrackerResponse {
                         .map(Some)?;
                 }
                 (b"peers", value) => {
-                    let p = value.try_into_bytes().unwrap().to_vec();
-                    let mut v = vec![];
-                    for i in (0..p.len()).step_by(6) {
-                        v.push(SocketAddr::from((
-                            [p[i], p[i + 1], p[i + 2], p[i + 3]],
-                            ((p[i + 4] as u16) << 8) + p[i + 5] as u16,
-                        )));
-                    }
+                    let p = value.try_into_bytes().context("peers")?;
+                    //compact, 4 bytes of address and 2 of port each
+                    let v = p
+                        .chunks_exact(6)
+                        .map(|p| {
+                            SocketAddr::from((
+                                [p[0], p[1], p[2], p[3]],
+                                u16::from_be_bytes([p[4], p[5]]),
+                            ))
+                        })
+                        .collect();
                     peers = Some(v);
                 }
-                (unknown_field, _) => {
-                    println!(
-                        "Not done in TrackerResponse - {:?}",
-                        String::from_utf8_lossy(unknown_field)
-                    );
-                }
+                //complete, incomplete, warning message...
+                (key, _) => trace!(
+                    target: "tracker",
+                    key:% = String::from_utf8_lossy(key);
+                    "Ignoring key in response"
+                ),
             }
         }
 
@@ -65,84 +84,133 @@ impl FromBencode for TrackerResponse {
     }
 }
 
-pub fn connect_to_tracker(tf: &TorrentFile, content: &Content) -> Option<TrackerResponse> {
-    let conn = |tracker: &str, info_hash: &str| -> (u16, Vec<String>, Vec<u8>) {
-        let mut left = 0;
-        let mut downloaded = 0;
-        for p in &content.pieces {
-            let piece = p.lock().unwrap();
-            match piece.status {
-                PieceStatus::Available => downloaded += piece.size,
-                _ => left += piece.size,
-            }
+fn announce_url(
+    tracker: &str,
+    tf: &TorrentFile,
+    content: &Content,
+    stats: &StatsSnapshot,
+    port: u16,
+    event: &str,
+) -> String {
+    let mut left = 0;
+    for p in &content.pieces {
+        let piece = p.lock().unwrap();
+        if piece.status != PieceStatus::Available {
+            left += piece.size as u64;
         }
-        println!("Connecting to tracker {:?}", tracker);
-        let url = format!("{}{}info_hash={}&port=50658&uploaded=0&downloaded={}&left={}&corrupt=0&key=CFA4D362&event=started&numwant=200&compact=1&no_peer_id=1",
-            tracker,
-            if tracker.contains('?') {"&"} else {"?"},
-            info_hash,
-            downloaded,
-            left
+    }
+    format!("{}{}info_hash={}&port={}&uploaded={}&downloaded={}&left={}&corrupt={}&key=CFA4D362&event={}&numwant=200&compact=1&no_peer_id=1",
+        tracker,
+        if tracker.contains('?') {"&"} else {"?"},
+        tf.info_hash.as_string_url_encoded(),
+        port,
+        stats.payload_uploaded,
+        stats.payload_downloaded,
+        left,
+        stats.wasted_corrupt,
+        event
+    )
+}
+
+/// Announces `event=started` to one tracker after another until one
+/// answers, returns that tracker and its answer. Trackers that fail before
+/// the last go to `on_error`, the last one's error is returned.
+pub fn connect_to_tracker(
+    tf: &TorrentFile,
+    content: &Content,
+    stats: &StatsSnapshot,
+    port: u16,
+    on_error: &dyn Fn(TrackerError),
+) -> Result<(String, TrackerResponse), TrackerError> {
+    let conn = |tracker: &str| -> Result<TrackerResponse, TrackerError> {
+        let url = announce_url(tracker, tf, content, stats, port, "started");
+        debug!(
+            target: "tracker",
+            info_hash:% = tf.info_hash.as_string(),
+            url;
+            "Announcing started"
         );
-        println!("{:?}", url);
+        let (_, _, body) =
+            http::get(&url, ANNOUNCE_TIMEOUT).map_err(|source| TrackerError::Connection {
+                url: tracker.to_string(),
+                source,
+            })?;
+        TrackerResponse::from_bencode(&body).map_err(|e| TrackerError::InvalidResponse {
+            url: tracker.to_string(),
+            reason: e.to_string(),
+        })
+    };
 
-        http::get(&url)
+    let trackers: Vec<&String> = match &tf.announce_list {
+        Some(announce_list) => announce_list.iter().flatten().collect(),
+        None => vec![&tf.announce],
     };
-    let to_tracker_response = |body: Vec<u8>| -> Result<TrackerResponse, DecodeErrorWrapper> {
-        let tr = TrackerResponse::from_bencode(&body);
-        match tr {
-            Ok(tr) => Ok(tr),
-            Err(e) => Err(DecodeErrorWrapper { derr: e }),
+    let mut trackers = trackers.into_iter().peekable();
+    while let Some(tracker) = trackers.next() {
+        match conn(tracker) {
+            Ok(r) => return Ok((tracker.clone(), r)),
+            Err(e) if trackers.peek().is_none() => return Err(e),
+            Err(e) => on_error(e),
         }
-    };
+    }
+    //an empty announce list, nothing was tried
+    Err(TrackerError::InvalidResponse {
+        url: tf.announce.clone(),
+        reason: "no trackers to announce to".into(),
+    })
+}
 
-    let mut result;
+/// Tells `tracker` we are leaving, fails if it couldn't be done within `timeout`.
+pub fn announce_stopped(
+    tracker: &str,
+    tf: &TorrentFile,
+    content: &Content,
+    stats: &StatsSnapshot,
+    port: u16,
+    timeout: Duration,
+) -> Result<(), TrackerError> {
+    let url = announce_url(tracker, tf, content, stats, port, "stopped");
+    debug!(
+        target: "tracker",
+        info_hash:% = tf.info_hash.as_string(),
+        url;
+        "Announcing stopped"
+    );
+    http::get(&url, timeout)
+        .map(|_| ())
+        .map_err(|source| TrackerError::Connection {
+            url: tracker.to_string(),
+            source,
+        })
+}
 
-    if tf.announce_list != None {
-        for tracker_list in tf.announce_list.as_ref().unwrap() {
-            for tracker in tracker_list {
-                result = conn(
-                    tracker,
-                    &tf.info_hash.as_string_url_encoded(),
-                );
-                match to_tracker_response(result.2) {
-                    Ok(r) => return Some(r),
-                    Err(e) => println!("{}", e.unwrap()),
-                }
-            }
-        }
-    } else {
-        result = conn(
-            &tf.announce,
-            &tf.info_hash.as_string_url_encoded(),
-        );
-        match to_tracker_response(result.2) {
-            Ok(r) => return Some(r),
-            Err(e) => println!("{}", e.unwrap()),
+impl TrackerError {
+    /// The tracker, as it is in the torrent file.
+    pub fn url(&self) -> &str {
+        match self {
+            TrackerError::Connection { url, .. } | TrackerError::InvalidResponse { url, .. } => url,
         }
     }
-    None
 }
 
-/*
-    Why the hell bendy removed
-        impl<T: StdError + Send + Sync + 'static> From<T> for Error
-    I don't know, but now I have to write this ugly piece of code
-*/
-#[derive(Debug)]
-struct DecodeErrorWrapper {
-    derr: DecodeError,
-}
-
-impl<T: std::error::Error + Send + Sync + 'static> From<T> for DecodeErrorWrapper {
-    fn from(error: T) -> Self {
-        let derr = DecodeError::malformed_content(error);
-        DecodeErrorWrapper { derr }
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackerError::Connection { url, source } => {
+                write!(f, "couldn't reach {}: {}", url, source)
+            }
+            TrackerError::InvalidResponse { url, reason } => {
+                write!(f, "invalid response from {}: {}", url, reason)
+            }
+        }
     }
 }
 
-impl DecodeErrorWrapper {
-    fn unwrap(self) -> DecodeError {
-        self.derr
+impl std::error::Error for TrackerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TrackerError::Connection { source, .. } => Some(source),
+            TrackerError::InvalidResponse { .. } => None,
+        }
     }
 }
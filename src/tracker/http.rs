@@ -1,16 +1,62 @@
-use std::io::{Read, Write};
-use std::net::{Shutdown, TcpStream};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-/// Send GET with fixed headers
-pub fn get(url: &str) -> (u16, Vec<String>, Vec<u8>) {
-    let announce_path = parse_url(url);
-    let mut stream = TcpStream::connect(&format!("{}:{}", announce_path.0, announce_path.1))
-        .expect("Cannot connect");
+/// Send GET with fixed headers, giving up once `timeout` has passed.
+pub fn get(url: &str, timeout: Duration) -> io::Result<(u16, Vec<String>, Vec<u8>)> {
+    let deadline = Instant::now() + timeout;
+    let left = || {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match left.is_zero() {
+            true => Err(io::Error::from(ErrorKind::TimedOut)),
+            false => Ok(left),
+        }
+    };
+    let (host, port, path) = parse_url(url)?;
+    let addr = resolve(&host, port, left()?)?;
+    let mut stream = TcpStream::connect_timeout(&addr, left()?)?;
+    stream.set_write_timeout(Some(left()?))?;
+    stream.write_all(request(&host, &path).as_bytes())?;
+
+    let mut response = vec![];
+    let mut buf = [0; 4096];
+    loop {
+        //a timeout per read, it has to be shortened as the deadline nears
+        stream.set_read_timeout(Some(left()?))?;
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => response.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+    Ok(parse_lines(get_lines(&response)))
+}
 
+/// The first address of `host`. The lookup itself can't be given a timeout,
+/// it runs on its own thread and is left behind if it takes too long.
+fn resolve(host: &str, port: u16, timeout: Duration) -> io::Result<SocketAddr> {
+    let (sender, receiver) = mpsc::channel();
+    let host = host.to_string();
+    thread::spawn(move || {
+        let addrs = (host.as_str(), port).to_socket_addrs();
+        let _ = sender.send(addrs.map(|mut addrs| addrs.next()));
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(addr) => addr?.ok_or_else(|| io::Error::new(ErrorKind::NotFound, "no address for host")),
+        Err(_) => Err(io::Error::new(ErrorKind::TimedOut, "host lookup timed out")),
+    }
+}
+
+/// The request line and fixed headers.
+fn request(host: &str, path: &str) -> String {
     let mut body = String::new();
-    body.push_str(format!("GET {} HTTP/1.1", &announce_path.2).as_str());
+    body.push_str(format!("GET {} HTTP/1.1", path).as_str());
     body.push_str("\r\n");
-    body.push_str(format!("Host: {}", &announce_path.0).as_str());
+    body.push_str(format!("Host: {}", host).as_str());
     body.push_str("\r\n");
     body.push_str("User-Agent: teatorrent/0.0.3");
     body.push_str("\r\n");
@@ -23,21 +69,10 @@ pub fn get(url: &str) -> (u16, Vec<String>, Vec<u8>) {
     body.push_str("Connection: close");
     body.push_str("\r\n");
     body.push_str("\r\n");
-    stream
-        .write_all(body.as_bytes())
-        .expect("Cannot write bytes");
-
-    let mut response = vec![];
-    stream
-        .read_to_end(&mut response)
-        .expect("Cannot read response");
-
-    let response = parse_lines(get_lines(&response));
-    stream.shutdown(Shutdown::Both).expect("Shutdown failed");
-    response
+    body
 }
 
-fn get_lines(response: &Vec<u8>) -> Vec<&[u8]> {
+fn get_lines(response: &[u8]) -> Vec<&[u8]> {
     let mut start = 0;
     let mut lines = vec![];
     for index in 1..response.len() {
@@ -54,32 +89,30 @@ fn parse_lines(lines: Vec<&[u8]>) -> (u16, Vec<String>, Vec<u8>) {
     //TODO: PARSE STATUS CODE!!!!
     let status_code: u16 = 0;
     let mut headers: Vec<String> = vec![];
-    let body: Vec<u8>;
 
     let _status_code_string = String::from_utf8(lines[0].to_vec());
     for line in &lines[1..] {
         if line == &[] {
             break;
         }
-        headers.push(String::from_utf8(line.to_vec()).unwrap());
+        headers.push(String::from_utf8_lossy(line).into_owned());
     }
 
-    body = lines.last().unwrap().to_vec();
+    let body = lines.last().unwrap().to_vec();
 
     (status_code, headers, body)
 }
 
-//host path
-fn parse_url(url: &str) -> (String, u16, String) {
-    let parsed = url::Url::parse(url).unwrap();
-    let host = parsed.host_str().unwrap();
-    let port = parsed.port_or_known_default();
+//host port path
+fn parse_url(url: &str) -> io::Result<(String, u16, String)> {
+    let invalid = |what: &str| io::Error::new(ErrorKind::InvalidInput, format!("{}: {}", what, url));
+    let parsed = url::Url::parse(url).map_err(|_| invalid("invalid url"))?;
+    let host = parsed.host_str().ok_or_else(|| invalid("no host in url"))?;
+    let port = parsed
+        .port_or_known_default()
+        .ok_or_else(|| invalid("no port for url"))?;
     let path = parsed.path();
-    let query = parsed.query().unwrap();
+    let query = parsed.query().unwrap_or_default();
 
-    (
-        format!("{}", host),
-        port.unwrap(),
-        format!("{}?{}", path, query),
-    )
+    Ok((host.to_string(), port, format!("{}?{}", path, query)))
 }
@@ -0,0 +1,374 @@
+//! Peer wire messages and what can go wrong reading them.
+//!
+//! Nothing a peer sends is trusted, a message that doesn't fit its kind is
+//! a `PeerError` and costs the peer its connection, never a panic.
+use crate::BLOCK_SIZE;
+use std::fmt;
+use std::io;
+
+/// Longest message taken besides a bitfield: a piece message with a
+/// block twice the size we ask for.
+const MAX_MESSAGE: usize = 9 + 2 * BLOCK_SIZE as usize;
+
+/*
+    keep-alive: <len=0000>
+    choke: <len=0001><id=0>
+    unchoke: <len=0001><id=1>
+    interested: <len=0001><id=2>
+    not interested: <len=0001><id=3>
+    have: <len=0005><id=4><piece index>
+    bitfield: <len=0001+X><id=5><bitfield>
+    request: <len=0013><id=6><index><begin><length>
+    piece: <len=0009+X><id=7><index><begin><block>
+    cancel: <len=0013><id=8><index><begin><length>
+    port: <len=0003><id=9><listen-port>
+*/
+#[derive(Debug)]
+pub(crate) enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request(u32, u32, u32),
+    Piece(u32, u32, Vec<u8>),
+    Cancel(u32, u32, u32),
+    Port(u16),
+}
+
+/// Why a connection to a peer was given up.
+#[derive(Debug)]
+pub enum PeerError {
+    /// Reading or writing the socket failed, the peer may just have left.
+    Io(io::Error),
+    UnknownMessage(u8),
+    /// Not the length a message of this id has.
+    BadLength { id: u8, length: usize },
+    /// Longer than any message we take, it isn't even read.
+    TooLong(usize),
+    /// A have for a piece the torrent doesn't have.
+    InvalidPiece(u32),
+    /// Bits set past the last piece.
+    InvalidBitfield,
+    /// Not a BitTorrent handshake, or one for another torrent.
+    InvalidHandshake,
+}
+
+impl PeerError {
+    /// Whether the peer broke the protocol, rather than the connection
+    /// failing.
+    pub fn is_violation(&self) -> bool {
+        !matches!(self, PeerError::Io(_))
+    }
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerError::Io(e) => write!(f, "connection failed: {}", e),
+            PeerError::UnknownMessage(id) => write!(f, "unknown message id {}", id),
+            PeerError::BadLength { id, length } => {
+                write!(f, "message {} can't be {} bytes long", id, length)
+            }
+            PeerError::TooLong(length) => write!(f, "message of {} bytes is too long", length),
+            PeerError::InvalidPiece(piece) => write!(f, "there is no piece {}", piece),
+            PeerError::InvalidBitfield => write!(f, "bitfield has bits past the last piece"),
+            PeerError::InvalidHandshake => write!(f, "handshake isn't for this torrent"),
+        }
+    }
+}
+
+impl std::error::Error for PeerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PeerError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PeerError {
+    fn from(e: io::Error) -> Self {
+        PeerError::Io(e)
+    }
+}
+
+/// Bytes of a bitfield for `piece_count` pieces.
+pub(crate) fn bitfield_len(piece_count: u32) -> usize {
+    piece_count.div_ceil(8) as usize
+}
+
+/// Longest message a peer of a torrent with `piece_count` pieces may send.
+pub(crate) fn max_message_len(piece_count: u32) -> usize {
+    MAX_MESSAGE.max(1 + bitfield_len(piece_count))
+}
+
+/// Splits the first whole message off `buf`, with how many bytes it took.
+/// `None` until all of it has arrived.
+pub(crate) fn frame(
+    buf: &[u8],
+    piece_count: u32,
+) -> Result<Option<(PeerMessage, usize)>, PeerError> {
+    let Some(prefix) = buf.get(..4) else {
+        return Ok(None);
+    };
+    let len = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+    //checked before waiting for it, a huge length would never arrive
+    if len > max_message_len(piece_count) {
+        return Err(PeerError::TooLong(len));
+    }
+    match buf.get(4..4 + len) {
+        Some(message) => Ok(Some((PeerMessage::parse(message, piece_count)?, 4 + len))),
+        None => Ok(None),
+    }
+}
+
+impl PeerMessage {
+    /// `message` is everything after the length prefix.
+    pub(crate) fn parse(message: &[u8], piece_count: u32) -> Result<PeerMessage, PeerError> {
+        let Some((&id, body)) = message.split_first() else {
+            return Ok(PeerMessage::KeepAlive);
+        };
+        let expect = |length: usize| match body.len() == length {
+            true => Ok(()),
+            false => Err(PeerError::BadLength {
+                id,
+                length: message.len(),
+            }),
+        };
+        //only called once the length is checked
+        let int = |at: usize| u32::from_be_bytes(body[at..at + 4].try_into().unwrap());
+        let message = match id {
+            0 => expect(0).map(|_| PeerMessage::Choke)?,
+            1 => expect(0).map(|_| PeerMessage::Unchoke)?,
+            2 => expect(0).map(|_| PeerMessage::Interested)?,
+            3 => expect(0).map(|_| PeerMessage::NotInterested)?,
+            4 => {
+                expect(4)?;
+                let piece = int(0);
+                if piece >= piece_count {
+                    return Err(PeerError::InvalidPiece(piece));
+                }
+                PeerMessage::Have(piece)
+            }
+            5 => {
+                expect(bitfield_len(piece_count))?;
+                let spare = (8 - piece_count % 8) % 8;
+                if body.last().is_some_and(|last| last & ((1u8 << spare) - 1) != 0) {
+                    return Err(PeerError::InvalidBitfield);
+                }
+                PeerMessage::Bitfield(body.to_vec())
+            }
+            6 => expect(12).map(|_| PeerMessage::Request(int(0), int(4), int(8)))?,
+            7 if body.len() >= 8 => PeerMessage::Piece(int(0), int(4), body[8..].to_vec()),
+            7 => {
+                return Err(PeerError::BadLength {
+                    id,
+                    length: message.len(),
+                })
+            }
+            8 => expect(12).map(|_| PeerMessage::Cancel(int(0), int(4), int(8)))?,
+            9 => expect(2).map(|_| PeerMessage::Port(u16::from_be_bytes([body[0], body[1]])))?,
+            _ => return Err(PeerError::UnknownMessage(id)),
+        };
+        Ok(message)
+    }
+
+    /// With the length prefix, ready to be sent.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut body = vec![];
+        match self {
+            PeerMessage::KeepAlive => (),
+            PeerMessage::Choke => body.push(0),
+            PeerMessage::Unchoke => body.push(1),
+            PeerMessage::Interested => body.push(2),
+            PeerMessage::NotInterested => body.push(3),
+            PeerMessage::Have(piece) => {
+                body.push(4);
+                body.extend(piece.to_be_bytes());
+            }
+            PeerMessage::Bitfield(field) => {
+                body.push(5);
+                body.extend(field);
+            }
+            PeerMessage::Request(index, begin, length) => {
+                body.push(6);
+                body.extend([index, begin, length].map(|int| int.to_be_bytes()).concat());
+            }
+            PeerMessage::Piece(index, begin, block) => {
+                body.push(7);
+                body.extend(index.to_be_bytes());
+                body.extend(begin.to_be_bytes());
+                body.extend(block);
+            }
+            PeerMessage::Cancel(index, begin, length) => {
+                body.push(8);
+                body.extend([index, begin, length].map(|int| int.to_be_bytes()).concat());
+            }
+            PeerMessage::Port(port) => {
+                body.push(9);
+                body.extend(port.to_be_bytes());
+            }
+        }
+        let mut message = (body.len() as u32).to_be_bytes().to_vec();
+        message.append(&mut body);
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_prefix(message: &[u8]) -> Vec<u8> {
+        let mut buf = (message.len() as u32).to_be_bytes().to_vec();
+        buf.extend_from_slice(message);
+        buf
+    }
+
+    #[test]
+    fn short_frames_wait() {
+        assert!(frame(&[], 10).unwrap().is_none());
+        assert!(frame(&[0, 0, 0], 10).unwrap().is_none());
+        let have = PeerMessage::Have(3).to_bytes();
+        for end in 4..have.len() {
+            assert!(frame(&have[..end], 10).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn frame_takes_one_message() {
+        let mut buf = PeerMessage::Have(3).to_bytes();
+        buf.extend(PeerMessage::KeepAlive.to_bytes());
+        buf.extend(PeerMessage::Unchoke.to_bytes());
+        let (message, used) = frame(&buf, 10).unwrap().unwrap();
+        assert!(matches!(message, PeerMessage::Have(3)));
+        assert_eq!(used, 9);
+        let (message, used) = frame(&buf[9..], 10).unwrap().unwrap();
+        assert!(matches!(message, PeerMessage::KeepAlive));
+        assert_eq!(used, 4);
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_before_they_arrive() {
+        let len = MAX_MESSAGE as u32 + 1;
+        assert!(matches!(
+            frame(&len.to_be_bytes(), 10),
+            Err(PeerError::TooLong(l)) if l == len as usize
+        ));
+        let len = MAX_MESSAGE as u32;
+        assert!(frame(&len.to_be_bytes(), 10).unwrap().is_none());
+        assert!(matches!(
+            frame(&u32::MAX.to_be_bytes(), 10),
+            Err(PeerError::TooLong(_))
+        ));
+        //a bitfield can be longer than a piece message for a big torrent
+        let piece_count = 8 * MAX_MESSAGE as u32;
+        let len = max_message_len(piece_count) as u32;
+        assert!(frame(&len.to_be_bytes(), piece_count).unwrap().is_none());
+    }
+
+    #[test]
+    fn bad_lengths() {
+        for message in [
+            &[0, 0][..],
+            &[1, 0],
+            &[2, 0, 0],
+            &[3, 0],
+            &[4, 0, 0, 0],
+            &[4, 0, 0, 0, 0, 0],
+            &[5],
+            &[5, 0, 0, 0],
+            &[6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            &[7, 0, 0, 0, 0, 0, 0, 0],
+            &[8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            &[9, 0],
+        ] {
+            assert!(
+                matches!(
+                    PeerMessage::parse(message, 10),
+                    Err(PeerError::BadLength { id, length }) if id == message[0] && length == message.len()
+                ),
+                "{:?}",
+                message
+            );
+        }
+        assert!(matches!(
+            frame(&with_prefix(&[0, 0]), 10),
+            Err(PeerError::BadLength { id: 0, length: 2 })
+        ));
+    }
+
+    #[test]
+    fn unknown_ids() {
+        assert!(matches!(
+            PeerMessage::parse(&[20, 0], 10),
+            Err(PeerError::UnknownMessage(20))
+        ));
+    }
+
+    #[test]
+    fn have_out_of_range() {
+        assert!(matches!(
+            PeerMessage::parse(&[4, 0, 0, 0, 9], 10),
+            Ok(PeerMessage::Have(9))
+        ));
+        assert!(matches!(
+            PeerMessage::parse(&[4, 0, 0, 0, 10], 10),
+            Err(PeerError::InvalidPiece(10))
+        ));
+        assert!(matches!(
+            PeerMessage::parse(&[4, 255, 255, 255, 255], 10),
+            Err(PeerError::InvalidPiece(u32::MAX))
+        ));
+    }
+
+    #[test]
+    fn bitfield_spare_bits() {
+        //10 pieces, the last 6 bits of the second byte are spare
+        assert!(matches!(
+            PeerMessage::parse(&[5, 0xff, 0b1100_0000], 10),
+            Ok(PeerMessage::Bitfield(_))
+        ));
+        for last in [0b0010_0000, 0b0000_0001] {
+            assert!(matches!(
+                PeerMessage::parse(&[5, 0xff, last], 10),
+                Err(PeerError::InvalidBitfield)
+            ));
+        }
+        //no spare bits
+        assert!(matches!(
+            PeerMessage::parse(&[5, 0xff], 8),
+            Ok(PeerMessage::Bitfield(_))
+        ));
+    }
+
+    #[test]
+    fn piece_blocks_can_be_empty() {
+        assert!(matches!(
+            PeerMessage::parse(&[7, 0, 0, 0, 1, 0, 0, 0, 2], 10),
+            Ok(PeerMessage::Piece(1, 2, block)) if block.is_empty()
+        ));
+    }
+
+    #[test]
+    fn round_trip() {
+        let messages = [
+            PeerMessage::Choke,
+            PeerMessage::Interested,
+            PeerMessage::Bitfield(vec![0xff, 0xc0]),
+            PeerMessage::Request(1, 2, 3),
+            PeerMessage::Piece(1, 2, vec![7; 5]),
+            PeerMessage::Cancel(1, 2, 3),
+            PeerMessage::Port(6881),
+        ];
+        for message in messages {
+            let bytes = message.to_bytes();
+            let (parsed, used) = frame(&bytes, 10).unwrap().unwrap();
+            assert_eq!(used, bytes.len());
+            assert_eq!(format!("{:?}", parsed), format!("{:?}", message));
+        }
+    }
+}